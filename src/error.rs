@@ -0,0 +1,239 @@
+use super::execute::BackendError;
+use sqlparser::parser::ParserError;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PowerSqlError {
+    // powersql.toml, command line options or environment
+    Config {
+        message: String,
+    },
+    Io {
+        path: String,
+        message: String,
+    },
+    Parse {
+        file: String,
+        line: Option<u64>,
+        column: Option<u64>,
+        message: String,
+    },
+    MissingModel {
+        model: String,
+    },
+    // Every cycle lists the models starting and ending with the same model,
+    // each model referring to the next one
+    Cycle {
+        cycles: Vec<Vec<String>>,
+        files: HashMap<String, String>,
+    },
+    Type {
+        model: Option<String>,
+        column: Option<String>,
+        message: String,
+    },
+    Backend {
+        model: Option<String>,
+        error: BackendError,
+    },
+    // `hook` names the failed hook, like `post_hook 2`
+    Hook {
+        model: Option<String>,
+        hook: String,
+        sql: String,
+        error: BackendError,
+    },
+}
+
+/// Finds the position in messages like `... at Line: 2, Column 5 ...`
+fn parse_location(message: &str) -> (Option<u64>, Option<u64>) {
+    let number_after = |prefix: &str| {
+        message.find(prefix).and_then(|start| {
+            message[start + prefix.len()..]
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+                .parse()
+                .ok()
+        })
+    };
+    (number_after("Line: "), number_after("Column "))
+}
+
+impl PowerSqlError {
+    pub fn config(message: impl Into<String>) -> PowerSqlError {
+        PowerSqlError::Config {
+            message: message.into(),
+        }
+    }
+
+    pub fn parse(file: &str, err: ParserError) -> PowerSqlError {
+        let message = match err {
+            ParserError::TokenizerError(message) => message,
+            ParserError::ParserError(message) => message,
+        };
+        let (line, column) = parse_location(&message);
+        PowerSqlError::Parse {
+            file: file.to_string(),
+            line,
+            column,
+            message,
+        }
+    }
+
+    pub fn type_error(column: Option<&str>, message: impl Into<String>) -> PowerSqlError {
+        PowerSqlError::Type {
+            model: None,
+            column: column.map(|x| x.to_string()),
+            message: message.into(),
+        }
+    }
+
+    /// Adds the model in which the error occurred
+    pub fn in_model(self, name: &str) -> PowerSqlError {
+        match self {
+            PowerSqlError::Type {
+                model: None,
+                column,
+                message,
+            } => PowerSqlError::Type {
+                model: Some(name.to_string()),
+                column,
+                message,
+            },
+            PowerSqlError::Backend { model: None, error } => PowerSqlError::Backend {
+                model: Some(name.to_string()),
+                error,
+            },
+            PowerSqlError::Hook {
+                model: None,
+                hook,
+                sql,
+                error,
+            } => PowerSqlError::Hook {
+                model: Some(name.to_string()),
+                hook,
+                sql,
+                error,
+            },
+            err => err,
+        }
+    }
+
+    /// Process exit code, so scripts can distinguish the kind of failure.
+    /// Exit code 1 is used for failing data tests.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PowerSqlError::Config { .. } => 2,
+            PowerSqlError::Io { .. } => 3,
+            PowerSqlError::Parse { .. } => 4,
+            PowerSqlError::MissingModel { .. } => 5,
+            PowerSqlError::Cycle { .. } => 6,
+            PowerSqlError::Type { .. } => 7,
+            PowerSqlError::Backend { .. } | PowerSqlError::Hook { .. } => 8,
+        }
+    }
+}
+
+impl fmt::Display for PowerSqlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowerSqlError::Config { message } => write!(f, "Configuration error: {}", message),
+            PowerSqlError::Io { path, message } => {
+                write!(f, "Error while reading {}: {}", path, message)
+            }
+            PowerSqlError::Parse {
+                file,
+                line: Some(line),
+                column: Some(column),
+                message,
+            } => write!(
+                f,
+                "Parse error in {}:{}:{}: {}",
+                file, line, column, message
+            ),
+            PowerSqlError::Parse { file, message, .. } => {
+                write!(f, "Parse error in {}: {}", file, message)
+            }
+            PowerSqlError::MissingModel { model } => write!(f, "Model {} not found", model),
+            PowerSqlError::Cycle { cycles, files } => {
+                write!(f, "Circular dependencies detected:")?;
+                for cycle in cycles {
+                    write!(f, "\n  {}", cycle.join(" -> "))?;
+                    for model in cycle.iter().skip(1) {
+                        if let Some(file) = files.get(model) {
+                            write!(f, "\n    {} in {}", model, file)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            PowerSqlError::Type {
+                model,
+                column,
+                message,
+            } => {
+                write!(f, "Type error")?;
+                if let Some(model) = model {
+                    write!(f, " in model {}", model)?;
+                }
+                if let Some(column) = column {
+                    write!(f, " in column {}", column)?;
+                }
+                write!(f, ": {}", message)
+            }
+            PowerSqlError::Backend {
+                model: Some(model),
+                error,
+            } => write!(f, "Error in model {}: {}", model, error),
+            PowerSqlError::Backend { model: None, error } => write!(f, "{}", error),
+            PowerSqlError::Hook {
+                model,
+                hook,
+                sql,
+                error,
+            } => {
+                write!(f, "Error in {}", hook)?;
+                if let Some(model) = model {
+                    write!(f, " of model {}", model)?;
+                }
+                write!(f, ": {}\n  {}", error, sql)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PowerSqlError {}
+
+/// Problem in a file that is reported without stopping the rest of the project from loading
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub file: String,
+    pub line: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "Warning in {}:{}: {}", self.file, line, self.message),
+            None => write!(f, "Warning in {}: {}", self.file, self.message),
+        }
+    }
+}
+
+impl From<BackendError> for PowerSqlError {
+    fn from(error: BackendError) -> Self {
+        PowerSqlError::Backend { model: None, error }
+    }
+}
+
+#[test]
+fn test_parse_location() {
+    assert_eq!(
+        parse_location("Tokenizer Error at Line: 3, Column 14, unhandled char '#'"),
+        (Some(3), Some(14))
+    );
+    assert_eq!(parse_location("Expected ), found: EOF"), (None, None));
+}
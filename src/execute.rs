@@ -1,8 +1,18 @@
-use sqlparser::ast::Statement;
+use super::parser::PowerSqlDialect;
+use super::profile::Profile;
+use sqlparser::ast::{Expr, Ident, ObjectName, Statement};
+use sqlparser::parser::Parser;
 
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "postgres")]
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+#[cfg(any(feature = "bigquery", feature = "sqlite"))]
+use std::sync::{Arc, Mutex};
 #[cfg(feature = "postgres")]
-use tokio_postgres::{types, Client, NoTls};
+use tokio_postgres::{error::SqlState, types, Client, NoTls};
 #[cfg(feature = "bigquery")]
 extern crate google_bigquery2 as bigquery2;
 #[cfg(feature = "bigquery")]
@@ -16,29 +26,377 @@ use async_trait::async_trait;
 use bigquery2::{Bigquery, DatasetReference, Error, QueryRequest, QueryResponse};
 #[cfg(feature = "bigquery")]
 use oauth2::ServiceAccountAccess;
+#[cfg(feature = "sqlite")]
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
 
 #[async_trait]
-pub trait Executor {
-    async fn new() -> Result<Self, String>
+pub trait Executor: Send {
+    async fn new(profile: &Profile) -> Result<Self, BackendError>
     where
         Self: Sized;
-    async fn execute(&mut self, name: &str, stmt: &mut Statement) -> Result<(), String>;
-    async fn execute_raw(&mut self, stmt: &Statement) -> Result<(), BackendError>;
-    async fn query_bool(&mut self, query: &str) -> Result<bool, String>;
+    async fn execute(&mut self, stmt: &mut Statement) -> Result<(), BackendError>;
+    /// Executes SQL as written, like hooks with statements unknown to the parser
+    async fn execute_raw(&mut self, sql: &str) -> Result<(), BackendError>;
+    async fn query_bool(&mut self, query: &str) -> Result<bool, BackendError>;
+    /// Executes the statements in a single transaction when the backend supports it
+    async fn execute_batch(&mut self, stmts: &[Statement]) -> Result<(), BackendError>;
+    async fn table_exists(&mut self, name: &ObjectName) -> Result<bool, BackendError>;
+    /// Reads the columns of the tables and views from the catalog of the database
+    async fn get_schemas(&mut self) -> Result<Vec<TableSchema>, BackendError>;
+
+    /// Creates the table on the first run, afterwards only adds the new rows
+    async fn execute_incremental(
+        &mut self,
+        stmt: &mut Statement,
+        incremental: &Incremental,
+    ) -> Result<(), BackendError> {
+        if !self.table_exists(model_name(stmt)).await? {
+            return self.execute(stmt).await;
+        }
+        let stmts = incremental.statements(stmt)?;
+        self.execute_batch(&stmts).await
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSchema {
+    pub name: String,
+    // Column names with the type as reported by the database
+    pub columns: Vec<(String, String)>,
+}
+
+/// Groups (table, column, type) rows ordered by table into tables
+fn group_columns(rows: Vec<(String, String, String)>) -> Vec<TableSchema> {
+    let mut res: Vec<TableSchema> = vec![];
+    for (table, column, data_type) in rows {
+        match res.last_mut() {
+            Some(last) if last.name == table => last.columns.push((column, data_type)),
+            _ => res.push(TableSchema {
+                name: table,
+                columns: vec![(column, data_type)],
+            }),
+        }
+    }
+    res
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incremental {
+    // Rows with a key in the new rows are replaced
+    pub unique_key: Option<String>,
+    // Condition on the rows of the query selecting the new rows
+    pub filter: Option<Expr>,
+}
+
+impl Incremental {
+    pub fn statements(&self, stmt: &Statement) -> Result<Vec<Statement>, BackendError> {
+        let (name, query) = match stmt {
+            Statement::CreateTable {
+                name,
+                query: Some(query),
+                ..
+            } => (name, query),
+            _ => {
+                return Err(BackendError::query(
+                    "Incremental models should use CREATE TABLE AS",
+                ))
+            }
+        };
+
+        let new_rows = match &self.filter {
+            Some(filter) => format!(
+                "SELECT * FROM ({query}) AS powersql_new WHERE {filter}",
+                query = query,
+                filter = filter
+            ),
+            None => format!("SELECT * FROM ({query}) AS powersql_new", query = query),
+        };
+
+        let sql = match &self.unique_key {
+            // The new rows are stored first, as deleting rows can change the result of the
+            // query when the filter refers to the table itself
+            Some(key) => format!(
+                "DROP TABLE IF EXISTS {staging}; \
+                 CREATE TABLE {staging} AS {new_rows}; \
+                 DELETE FROM {name} WHERE {key} IN (SELECT {key} FROM {staging}); \
+                 INSERT INTO {name} SELECT * FROM {staging}; \
+                 DROP TABLE {staging}",
+                staging = with_suffix(name, "__powersql_incremental"),
+                name = name,
+                key = key,
+                new_rows = new_rows
+            ),
+            None => format!(
+                "INSERT INTO {name} {new_rows}",
+                name = name,
+                new_rows = new_rows
+            ),
+        };
+
+        Parser::parse_sql(&PowerSqlDialect {}, &sql).map_err(|err| {
+            BackendError::query(format!(
+                "Error in incremental statements of {}: {}",
+                name, err
+            ))
+        })
+    }
+}
+
+/// Name of the relation created by a model statement
+pub fn model_name(stmt: &Statement) -> &ObjectName {
+    match stmt {
+        Statement::CreateView { name, .. } | Statement::CreateTable { name, .. } => name,
+        _ => unreachable!("Only create table and create view supported"),
+    }
+}
+
+/// Name with every part quoted, so each part is used as written
+fn quoted(name: &ObjectName) -> ObjectName {
+    ObjectName(
+        name.0
+            .iter()
+            .map(|part| Ident {
+                value: part.value.clone(),
+                quote_style: Some('"'),
+            })
+            .collect(),
+    )
+}
+
+// PostgreSQL truncates longer identifiers
+const MAX_IDENTIFIER_BYTES: usize = 63;
+
+/// Name of another relation in the same schema, with a suffix added to the name.
+/// Long names are shortened to fit in an identifier, with a hash of the full name
+/// so shortened names of different relations stay distinct.
+fn with_suffix(name: &ObjectName, suffix: &str) -> ObjectName {
+    let mut name = name.clone();
+    if let Some(last) = name.0.last_mut() {
+        if last.value.len() + suffix.len() > MAX_IDENTIFIER_BYTES {
+            let mut hasher = DefaultHasher::new();
+            last.value.hash(&mut hasher);
+            let hash = format!("_{:08x}", hasher.finish() as u32);
+            let mut end = MAX_IDENTIFIER_BYTES.saturating_sub(suffix.len() + hash.len());
+            while !last.value.is_char_boundary(end) {
+                end -= 1;
+            }
+            last.value.truncate(end);
+            last.value.push_str(&hash);
+        }
+        last.value.push_str(suffix);
+    }
+    name
+}
+
+/// Schema of a qualified name, `None` for relations in the default schema
+fn schema_of(name: &ObjectName) -> Option<ObjectName> {
+    match name.0.split_last() {
+        Some((_, schema)) if !schema.is_empty() => Some(ObjectName(schema.to_vec())),
+        _ => None,
+    }
+}
+
+/// Copy of a model statement creating the relation under another name
+fn with_name(stmt: &Statement, new_name: ObjectName) -> Statement {
+    let mut stmt = stmt.clone();
+    match &mut stmt {
+        Statement::CreateView { name, .. } | Statement::CreateTable { name, .. } => {
+            *name = new_name;
+        }
+        _ => unreachable!("Only create table and create view supported"),
+    }
+    stmt
+}
+
+/// Kind of relation created by a model statement, as used in `DROP` and `ALTER`
+fn relation_kind(stmt: &Statement) -> &'static str {
+    match stmt {
+        Statement::CreateView {
+            materialized: true, ..
+        } => "MATERIALIZED VIEW",
+        Statement::CreateView { .. } => "VIEW",
+        _ => "TABLE",
+    }
 }
 
+fn join_statements(stmts: &[Statement]) -> String {
+    stmts
+        .iter()
+        .map(|x| format!("{}", x))
+        .collect::<Vec<_>>()
+        .join(";\n")
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum BackendError {
-    Message { message: String },
+    // Missing or invalid connection settings
+    Config {
+        message: String,
+    },
+    Connection {
+        message: String,
+    },
+    // The SQLSTATE (PostgreSQL), error reason (BigQuery) or result code (SQLite)
+    // is given in `code` when the backend provides one
+    Query {
+        code: Option<String>,
+        message: String,
+    },
 }
 
 impl BackendError {
-    fn get_message(self) -> String {
+    fn query(message: impl Into<String>) -> BackendError {
+        BackendError::Query {
+            code: None,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            BackendError::Message { message } => message,
+            BackendError::Config { message } => write!(f, "{}", message),
+            BackendError::Connection { message } => write!(f, "Connection error: {}", message),
+            BackendError::Query {
+                code: Some(code),
+                message,
+            } => write!(f, "{} ({})", message, code),
+            BackendError::Query {
+                code: None,
+                message,
+            } => write!(f, "{}", message),
         }
     }
 }
 
+/// Runs a blocking call on the blocking thread pool, so the models built
+/// by other executors keep running
+pub async fn blocking<T, F>(f: F) -> Result<T, BackendError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, BackendError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| BackendError::Connection {
+            message: format!("Backend task failed: {}", err),
+        })?
+}
+
+fn env_var(name: &str) -> Result<String, BackendError> {
+    env::var(name).map_err(|_x| BackendError::Config {
+        message: format!("{} not provided", name),
+    })
+}
+
+/// Setting from the profile, or else from the environment variable
+fn setting(value: &Option<String>, name: &str) -> Result<String, BackendError> {
+    match value {
+        Some(value) => Ok(value.clone()),
+        None => env_var(name),
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn postgres_error(err: tokio_postgres::Error) -> BackendError {
+    BackendError::Query {
+        code: err.code().map(|x| x.code().to_string()),
+        message: format!("{}", err),
+    }
+}
+
+#[cfg(feature = "bigquery")]
+fn bigquery_error(err: Error) -> BackendError {
+    match &err {
+        Error::BadRequest(response) => BackendError::Query {
+            code: response.error.errors.first().map(|x| x.reason.clone()),
+            message: response.error.message.clone(),
+        },
+        _ => BackendError::query(format!("{}", err)),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(err: rusqlite::Error) -> BackendError {
+    let code = match &err {
+        rusqlite::Error::SqliteFailure(failure, _) => Some(failure.extended_code.to_string()),
+        _ => None,
+    };
+    BackendError::Query {
+        code,
+        message: format!("{}", err),
+    }
+}
+
+/// Views depending on a relation, directly or through other views, with their kind and
+/// definition. Views come after the views they depend on.
+#[cfg(feature = "postgres")]
+const DEPENDENT_VIEWS: &str = "WITH RECURSIVE dependents(oid, depth) AS ( \
+     SELECT r.ev_class, 1 FROM pg_depend d JOIN pg_rewrite r ON r.oid = d.objid \
+     WHERE d.refobjid = to_regclass($1) AND r.ev_class <> d.refobjid \
+     UNION \
+     SELECT r.ev_class, dependents.depth + 1 FROM dependents \
+     JOIN pg_depend d ON d.refobjid = dependents.oid JOIN pg_rewrite r ON r.oid = d.objid \
+     WHERE r.ev_class <> d.refobjid) \
+     SELECT format('%I.%I', n.nspname, c.relname), c.relkind, pg_get_viewdef(c.oid) \
+     FROM dependents JOIN pg_class c ON c.oid = dependents.oid \
+     JOIN pg_namespace n ON n.oid = c.relnamespace \
+     GROUP BY c.oid, n.nspname, c.relname, c.relkind \
+     ORDER BY MAX(dependents.depth)";
+
+/// Statements restoring the grants, comments and owner of relation `$1` on the relation
+/// `$2` of kind `$3` replacing it. Column comments are kept for the columns of `$4`.
+#[cfg(feature = "postgres")]
+const RELATION_SETTINGS: &str = "SELECT format('GRANT %s ON %s TO %s%s', a.privilege_type, $2::text, \
+     CASE WHEN a.grantee = 0 THEN 'PUBLIC' ELSE quote_ident(pg_get_userbyid(a.grantee)) END, \
+     CASE WHEN a.is_grantable THEN ' WITH GRANT OPTION' ELSE '' END) \
+     FROM pg_class c, aclexplode(c.relacl) a WHERE c.oid = to_regclass($1) AND a.grantee <> c.relowner \
+     UNION ALL \
+     SELECT format('COMMENT ON %s %s IS %L', $3::text, $2::text, d.description) FROM pg_description d \
+     WHERE d.objoid = to_regclass($1) AND d.classoid = 'pg_class'::regclass AND d.objsubid = 0 \
+     UNION ALL \
+     SELECT format('COMMENT ON COLUMN %s.%I IS %L', $2::text, a.attname, d.description) \
+     FROM pg_description d JOIN pg_attribute a ON a.attrelid = d.objoid AND a.attnum = d.objsubid \
+     WHERE d.objoid = to_regclass($1) AND d.classoid = 'pg_class'::regclass AND d.objsubid > 0 \
+     AND a.attname IN (SELECT attname FROM pg_attribute WHERE attrelid = to_regclass($4) AND attnum > 0) \
+     UNION ALL \
+     SELECT format('ALTER %s %s OWNER TO %I', $3::text, $2::text, pg_get_userbyid(c.relowner)) \
+     FROM pg_class c WHERE c.oid = to_regclass($1) AND pg_get_userbyid(c.relowner) <> current_user";
+
+#[cfg(feature = "postgres")]
+fn view_kind(relkind: i8) -> &'static str {
+    match relkind as u8 {
+        b'm' => "MATERIALIZED VIEW",
+        _ => "VIEW",
+    }
+}
+
+/// Names (schema, table, column, type) rows like in the models: tables found through the
+/// search path without schema, tables hidden by a table earlier on the search path with schema
+#[cfg(feature = "postgres")]
+fn search_path_names(
+    rows: Vec<(String, String, String, String)>,
+    search_path: &[String],
+) -> Vec<(String, String, String)> {
+    let mut visible: HashMap<&str, usize> = HashMap::new();
+    for (schema, table, _, _) in rows.iter() {
+        if let Some(i) = search_path.iter().position(|x| x == schema) {
+            let entry = visible.entry(table.as_str()).or_insert(i);
+            *entry = (*entry).min(i);
+        }
+    }
+    rows.iter()
+        .map(|(schema, table, column, data_type)| {
+            let name = match search_path.iter().position(|x| x == schema) {
+                Some(i) if visible.get(table.as_str()) == Some(&i) => table.to_string(),
+                _ => format!("{}.{}", schema, table),
+            };
+            (name, column.to_string(), data_type.to_string())
+        })
+        .collect()
+}
+
 #[cfg(feature = "postgres")]
 pub struct Postgres {
     client: Client,
@@ -47,13 +405,13 @@ pub struct Postgres {
 #[async_trait]
 #[cfg(feature = "postgres")]
 impl Executor for Postgres {
-    async fn new() -> Result<Postgres, String> {
+    async fn new(profile: &Profile) -> Result<Postgres, BackendError> {
         // TODO, simplify, use TLS
-        let hostname = env::var("PG_HOSTNAME").map_err(|_x| "PG_HOSTNAME not provided")?;
-        let username = env::var("PG_USERNAME").map_err(|_x| "PG_USERNAME not provided")?;
-        let port = env::var("PG_PORT").map_err(|_x| "PG_PORT not provided")?;
-        let database = env::var("PG_DATABASE").map_err(|_x| "PG_DATABASE not provided")?;
-        let password = env::var("PG_PASSWORD").map_err(|_x| "PG_PASSWORD not provided")?;
+        let hostname = setting(&profile.host, "PG_HOSTNAME")?;
+        let username = setting(&profile.user, "PG_USERNAME")?;
+        let port = setting(&profile.port, "PG_PORT")?;
+        let database = setting(&profile.database, "PG_DATABASE")?;
+        let password = setting(&profile.password, "PG_PASSWORD")?;
 
         let url = format!(
             "postgresql://{username}:{password}@{hostname}:{port}/{database}",
@@ -63,80 +421,297 @@ impl Executor for Postgres {
             hostname = hostname,
             database = database,
         );
-        let (client, connection) = tokio_postgres::connect(&url, NoTls)
-            .await
-            .map_err(|_x| "Failed to connect")?;
+        let (client, connection) =
+            tokio_postgres::connect(&url, NoTls)
+                .await
+                .map_err(|e| BackendError::Connection {
+                    message: format!("{}", e),
+                })?;
         tokio::spawn(async move {
             if let Err(e) = connection.await {
                 eprintln!("Connection error: {}", e);
             }
         });
 
+        // Models without schema are created in the schema of the profile,
+        // other relations are still found in the default search path
+        if let Some(schema) = &profile.schema {
+            let schema = Ident {
+                value: schema.clone(),
+                quote_style: Some('"'),
+            };
+            client
+                .batch_execute(format!("CREATE SCHEMA IF NOT EXISTS {}", schema).as_str())
+                .await
+                .map_err(postgres_error)?;
+            client
+                .execute(
+                    "SELECT set_config('search_path', $1::text || ', ' || current_setting('search_path'), false)",
+                    &[&schema.to_string()],
+                )
+                .await
+                .map_err(postgres_error)?;
+        }
+
         Ok(Postgres { client })
     }
-    async fn execute(&mut self, name: &str, stmt: &mut Statement) -> Result<(), String> {
-        let _ = self
-            .client
-            .execute(
-                format!("DROP VIEW IF EXISTS \"{name}\" CASCADE", name = name,).as_str(),
-                &[],
-            )
-            .await;
+    async fn execute(&mut self, stmt: &mut Statement) -> Result<(), BackendError> {
+        // The model is built under a temporary name and swapped in within one transaction,
+        // so the existing relation stays available during the build and intact on failure
+        let name = quoted(model_name(stmt));
+        let new_name = with_suffix(&name, "__powersql_new");
 
-        let _ = self
-            .client
-            .execute(
-                format!("DROP TABLE IF EXISTS \"{name}\" CASCADE", name = name,).as_str(),
-                &[],
-            )
-            .await;
+        let mut transaction = self.client.transaction().await.map_err(postgres_error)?;
 
-        let transaction = self
-            .client
-            .transaction()
+        if let Some(schema) = schema_of(&name) {
+            transaction
+                .batch_execute(format!("CREATE SCHEMA IF NOT EXISTS {}", schema).as_str())
+                .await
+                .map_err(postgres_error)?;
+        }
+
+        let old_kind: Option<i8> = transaction
+            .query_opt(
+                "SELECT relkind FROM pg_class WHERE oid = to_regclass($1)",
+                &[&name.to_string()],
+            )
             .await
-            .map_err(|e| format!("PostgresError {}", e))?;
+            .map_err(postgres_error)?
+            .map(|row| row.get(0));
+
+        // A view with compatible columns is replaced in place, the views depending on it are kept
+        if old_kind == Some(b'v' as i8) && relation_kind(stmt) == "VIEW" {
+            let mut replace = with_name(stmt, name.clone());
+            if let Statement::CreateView { or_replace, .. } = &mut replace {
+                *or_replace = true;
+            }
+            let savepoint = transaction.transaction().await.map_err(postgres_error)?;
+            match savepoint
+                .batch_execute(format!("{}", replace).as_str())
+                .await
+            {
+                Ok(()) => {
+                    savepoint.commit().await.map_err(postgres_error)?;
+                    return transaction.commit().await.map_err(postgres_error);
+                }
+                // Columns were removed, renamed or changed type
+                Err(err) if err.code() == Some(&SqlState::INVALID_TABLE_DEFINITION) => {
+                    savepoint.rollback().await.map_err(postgres_error)?
+                }
+                Err(err) => return Err(postgres_error(err)),
+            }
+        }
 
         transaction
-            .batch_execute(format!("{}", stmt).as_str())
+            .batch_execute(format!("{}", with_name(stmt, new_name.clone())).as_str())
             .await
-            .map_err(|e| format!("PostgresError {}", e))?;
+            .map_err(postgres_error)?;
+
+        let drop_kind = match old_kind.map(|x| x as u8) {
+            Some(b'v') => Some("VIEW"),
+            Some(b'm') => Some("MATERIALIZED VIEW"),
+            Some(b'r') | Some(b'p') => Some("TABLE"),
+            _ => None,
+        };
+
+        // Views depending on the old relation are recreated on the new one in the same
+        // transaction, rather than dropped with CASCADE and missing until they are rebuilt
+        let dependents: Vec<(String, i8, String)> = match drop_kind {
+            Some(_) => transaction
+                .query(DEPENDENT_VIEWS, &[&name.to_string()])
+                .await
+                .map_err(postgres_error)?
+                .iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2)))
+                .collect(),
+            None => vec![],
+        };
+        // Dropping the relations removes their grants, comments and owner, they are set again
+        // on the new relations
+        let mut settings: Vec<String> = vec![];
+        if drop_kind.is_some() {
+            let rows = transaction
+                .query(
+                    RELATION_SETTINGS,
+                    &[
+                        &name.to_string(),
+                        &name.to_string(),
+                        &relation_kind(stmt),
+                        &new_name.to_string(),
+                    ],
+                )
+                .await
+                .map_err(postgres_error)?;
+            settings.extend(rows.iter().map(|row| row.get(0)));
+        }
+        for (view, kind, _) in dependents.iter() {
+            let rows = transaction
+                .query(RELATION_SETTINGS, &[view, view, &view_kind(*kind), view])
+                .await
+                .map_err(postgres_error)?;
+            settings.extend(rows.iter().map(|row| row.get(0)));
+        }
+
+        for (view, kind, _) in dependents.iter().rev() {
+            transaction
+                .batch_execute(format!("DROP {} {}", view_kind(*kind), view).as_str())
+                .await
+                .map_err(postgres_error)?;
+        }
+
+        // Other dependent objects, like foreign keys, make the drop fail instead of being removed
+        if let Some(kind) = drop_kind {
+            transaction
+                .batch_execute(format!("DROP {kind} {name}", kind = kind, name = name).as_str())
+                .await
+                .map_err(postgres_error)?;
+        }
 
         transaction
-            .commit()
+            .batch_execute(
+                format!(
+                    "ALTER {kind} {new_name} RENAME TO {name}",
+                    kind = relation_kind(stmt),
+                    new_name = new_name,
+                    name = name.0.last().unwrap()
+                )
+                .as_str(),
+            )
             .await
-            .map_err(|e| format!("PostgresError {}", e))?;
+            .map_err(postgres_error)?;
+
+        for (view, kind, definition) in dependents.iter() {
+            transaction
+                .batch_execute(
+                    format!(
+                        "CREATE {} {} AS {}",
+                        view_kind(*kind),
+                        view,
+                        definition.trim_end().trim_end_matches(';')
+                    )
+                    .as_str(),
+                )
+                .await
+                .map_err(postgres_error)?;
+        }
+        for setting in settings.iter() {
+            transaction
+                .batch_execute(setting.as_str())
+                .await
+                .map_err(postgres_error)?;
+        }
+
+        transaction.commit().await.map_err(postgres_error)?;
 
         Ok(())
     }
 
-    async fn execute_raw(&mut self, stmt: &Statement) -> Result<(), BackendError> {
+    async fn execute_raw(&mut self, sql: &str) -> Result<(), BackendError> {
         let _ = self
             .client
-            .execute(format!("{}", stmt).as_str(), &[])
+            .execute(sql, &[])
             .await
-            .map_err(|x| BackendError::Message {
-                message: format!("{}", x),
-            })?;
+            .map_err(postgres_error)?;
         Ok(())
     }
 
-    async fn query_bool(&mut self, query: &str) -> Result<bool, String> {
-        self.client
+    async fn query_bool(&mut self, query: &str) -> Result<bool, BackendError> {
+        let rows = self
+            .client
             .query(query, &[])
             .await
-            .map(|x| x[0].get(0))
-            .map_err(|x| format!("Failed to run query {}", x))
+            .map_err(postgres_error)?;
+        let value: Option<bool> = rows
+            .first()
+            .ok_or_else(|| BackendError::query("Expected a boolean, the query returned no rows"))?
+            .try_get(0)
+            .map_err(postgres_error)?;
+        value.ok_or_else(|| BackendError::query("Expected a boolean, got NULL"))
+    }
+
+    async fn execute_batch(&mut self, stmts: &[Statement]) -> Result<(), BackendError> {
+        let transaction = self.client.transaction().await.map_err(postgres_error)?;
+
+        transaction
+            .batch_execute(join_statements(stmts).as_str())
+            .await
+            .map_err(postgres_error)?;
+
+        transaction.commit().await.map_err(postgres_error)
+    }
+
+    async fn table_exists(&mut self, name: &ObjectName) -> Result<bool, BackendError> {
+        self.client
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM pg_class WHERE oid = to_regclass($1) AND relkind IN ('r', 'p'))",
+                &[&quoted(name).to_string()],
+            )
+            .await
+            .map(|x| x.get(0))
+            .map_err(postgres_error)
+    }
+
+    async fn get_schemas(&mut self) -> Result<Vec<TableSchema>, BackendError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT table_schema::text, table_name::text, column_name::text, data_type::text \
+                 FROM information_schema.columns \
+                 WHERE table_schema NOT IN ('pg_catalog', 'information_schema') \
+                 ORDER BY table_schema, table_name, ordinal_position",
+                &[],
+            )
+            .await
+            .map_err(postgres_error)?;
+        let search_path: Vec<String> = self
+            .client
+            .query_one("SELECT current_schemas(false)::text[]", &[])
+            .await
+            .map(|x| x.get(0))
+            .map_err(postgres_error)?;
+
+        Ok(group_columns(search_path_names(
+            rows.iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+                .collect(),
+            &search_path,
+        )))
     }
 }
 #[cfg(feature = "bigquery")]
 pub struct BigqueryRunner {
-    hub: Bigquery<hyper::Client, ServiceAccountAccess<hyper::Client>>,
+    // The client blocks, it's used from the blocking thread pool
+    hub: Arc<Mutex<Bigquery<hyper::Client, ServiceAccountAccess<hyper::Client>>>>,
     dataset_id: String,
     project_id: String,
     location: Option<String>,
 }
 
+/// String literal in BigQuery SQL
+#[cfg(feature = "bigquery")]
+fn bigquery_string(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// The boolean in the first column of the first row of a query result
+#[cfg(feature = "bigquery")]
+fn first_bool(res: &QueryResponse) -> Result<bool, BackendError> {
+    let value = res
+        .rows
+        .as_ref()
+        .ok_or_else(|| BackendError::query("Expected a boolean, the query returned no rows"))?
+        .first()
+        .and_then(|row| row.f.as_ref())
+        .and_then(|cells| cells.first())
+        .ok_or_else(|| BackendError::query("Expected a boolean, the query returned no rows"))?
+        .v
+        .as_ref()
+        .ok_or_else(|| BackendError::query("Expected a boolean, got NULL"))?;
+    value
+        .parse()
+        .map_err(|_| BackendError::query(format!("Expected a boolean, got {}", value)))
+}
+
 #[cfg(feature = "bigquery")]
 impl BigqueryRunner {
     fn build_query(&mut self, query: &str) -> QueryRequest {
@@ -153,33 +728,39 @@ impl BigqueryRunner {
         return query_request;
     }
 
-    fn run_query(&mut self, query: QueryRequest) -> Result<QueryResponse, BackendError> {
-        return self
-            .hub
-            .jobs()
-            .query(query, &self.project_id)
-            .doit()
-            .map(|(_r, q)| q)
-            .map_err(|x| match x {
-                _ => BackendError::Message {
-                    message: format!("{}", x),
-                },
-            });
+    async fn run_query(&self, query: QueryRequest) -> Result<QueryResponse, BackendError> {
+        let hub = self.hub.clone();
+        let project_id = self.project_id.clone();
+        blocking(move || {
+            hub.lock()
+                .unwrap()
+                .jobs()
+                .query(query, &project_id)
+                .doit()
+                .map(|(_r, q)| q)
+                .map_err(bigquery_error)
+        })
+        .await
     }
 }
 
 #[cfg(feature = "bigquery")]
 #[async_trait]
 impl Executor for BigqueryRunner {
-    async fn new() -> Result<BigqueryRunner, String> {
-        let key_file = env::var("GOOGLE_APPLICATION_CREDENTIALS")
-            .map_err(|_x| "GOOGLE_APPLICATION_CREDENTIALS not provided")?;
+    async fn new(profile: &Profile) -> Result<BigqueryRunner, BackendError> {
+        let key_file = setting(&profile.credentials, "GOOGLE_APPLICATION_CREDENTIALS")?;
 
-        let project_id = env::var("PROJECT_ID").map_err(|_x| "PROJECT_ID not provided")?;
-        let dataset_id = env::var("DATASET_ID").map_err(|_x| "DATASET_ID not provided")?;
-        let location = env::var("LOCATION").ok();
+        let project_id = setting(&profile.project, "PROJECT_ID")?;
+        let dataset_id = setting(&profile.schema, "DATASET_ID")?;
+        let location = profile
+            .location
+            .clone()
+            .or_else(|| env::var("LOCATION").ok());
 
-        let client_secret = oauth2::service_account_key_from_file(&key_file).unwrap();
+        let client_secret =
+            oauth2::service_account_key_from_file(&key_file).map_err(|e| BackendError::Config {
+                message: format!("Could not read {}: {}", key_file, e),
+            })?;
         let client = hyper::Client::with_connector(hyper::net::HttpsConnector::new(
             hyper_rustls::TlsClient::new(),
         ));
@@ -190,21 +771,37 @@ impl Executor for BigqueryRunner {
             )),
             access,
         );
-        return Ok(BigqueryRunner {
-            hub,
+        let mut runner = BigqueryRunner {
+            hub: Arc::new(Mutex::new(hub)),
             project_id,
             dataset_id,
             location,
-        });
+        };
+
+        // The dataset of a profile is created on first use, like schemas in PostgreSQL
+        if profile.schema.is_some() {
+            let query = runner.build_query(&format!(
+                "CREATE SCHEMA IF NOT EXISTS `{}`",
+                runner.dataset_id
+            ));
+            runner.run_query(query).await?;
+        }
+        Ok(runner)
     }
 
-    async fn execute_raw(&mut self, stmt: &Statement) -> Result<(), BackendError> {
-        let query = self.build_query(&format!("{}", stmt));
-        self.run_query(query)?;
+    async fn execute_raw(&mut self, sql: &str) -> Result<(), BackendError> {
+        let query = self.build_query(sql);
+        self.run_query(query).await?;
         Ok(())
     }
 
-    async fn execute(&mut self, name: &str, stmt: &mut Statement) -> Result<(), String> {
+    async fn execute(&mut self, stmt: &mut Statement) -> Result<(), BackendError> {
+        // Datasets of qualified names are created in the project of the connection
+        if let Some(dataset) = schema_of(model_name(stmt)) {
+            let query = self.build_query(&format!("CREATE SCHEMA IF NOT EXISTS {}", dataset));
+            self.run_query(query).await?;
+        }
+
         match stmt {
             Statement::CreateTable {
                 ref mut or_replace, ..
@@ -220,19 +817,598 @@ impl Executor for BigqueryRunner {
         };
 
         let query = self.build_query(&format!("{}", stmt));
-        self.run_query(query).map_err(|x| x.get_message())?;
+        self.run_query(query).await?;
 
         Ok(())
     }
 
-    async fn query_bool(&mut self, query: &str) -> Result<bool, String> {
+    async fn query_bool(&mut self, query: &str) -> Result<bool, BackendError> {
         let query = self.build_query(query);
-        let res = self.run_query(query).map_err(|x| x.get_message())?;
-        Ok(res.rows.unwrap()[0].clone().f.unwrap()[0]
-            .v
-            .as_ref()
+        let res = self.run_query(query).await?;
+        first_bool(&res)
+    }
+
+    async fn execute_batch(&mut self, stmts: &[Statement]) -> Result<(), BackendError> {
+        // Multiple statements are executed as a single script
+        let query = self.build_query(&join_statements(stmts));
+        self.run_query(query).await?;
+        Ok(())
+    }
+
+    async fn table_exists(&mut self, name: &ObjectName) -> Result<bool, BackendError> {
+        let parts: Vec<&str> = name.0.iter().map(|x| x.value.as_str()).collect();
+        let (project_id, dataset_id, name) = match parts.as_slice() {
+            [project_id, dataset_id, name] => (*project_id, *dataset_id, *name),
+            [dataset_id, name] => (self.project_id.as_str(), *dataset_id, *name),
+            _ => (
+                self.project_id.as_str(),
+                self.dataset_id.as_str(),
+                parts.last().copied().unwrap_or_default(),
+            ),
+        };
+        let query = format!(
+            "SELECT COUNT(*) > 0 FROM `{project_id}.{dataset_id}`.INFORMATION_SCHEMA.TABLES WHERE table_name = {name} AND table_type = 'BASE TABLE'",
+            project_id = project_id,
+            dataset_id = dataset_id,
+            name = bigquery_string(name)
+        );
+        self.query_bool(&query).await
+    }
+
+    async fn get_schemas(&mut self) -> Result<Vec<TableSchema>, BackendError> {
+        let hub = self.hub.clone();
+        let project_id = self.project_id.clone();
+        let dataset_id = self.dataset_id.clone();
+        blocking(move || {
+            let hub = hub.lock().unwrap();
+            let mut names = vec![];
+            let mut page_token = None;
+            loop {
+                let mut call = hub.tables().list(&project_id, &dataset_id);
+                if let Some(token) = &page_token {
+                    call = call.page_token(token);
+                }
+                let (_r, list) = call.doit().map_err(bigquery_error)?;
+                names.extend(
+                    list.tables
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|t| t.table_reference.and_then(|r| r.table_id)),
+                );
+                page_token = list.next_page_token;
+                if page_token.is_none() {
+                    break;
+                }
+            }
+
+            let mut res = vec![];
+            for name in names {
+                let (_r, table) = hub
+                    .tables()
+                    .get(&project_id, &dataset_id, &name)
+                    .doit()
+                    .map_err(bigquery_error)?;
+                let fields = table.schema.and_then(|s| s.fields).unwrap_or_default();
+                let columns = fields
+                    .into_iter()
+                    .map(|field| {
+                        let data_type = field.type_.unwrap_or_default();
+                        // Repeated fields are arrays of the field type
+                        let data_type = match field.mode.as_deref() {
+                            Some("REPEATED") => format!("ARRAY<{}>", data_type),
+                            _ => data_type,
+                        };
+                        (field.name.unwrap_or_default(), data_type)
+                    })
+                    .collect();
+                res.push(TableSchema { name, columns });
+            }
+            Ok(res)
+        })
+        .await
+    }
+}
+
+/// Catalog table of the attached database holding the relation
+#[cfg(feature = "sqlite")]
+fn sqlite_master(name: &ObjectName) -> String {
+    match schema_of(name) {
+        Some(schema) => format!("{}.sqlite_master", schema),
+        None => "sqlite_master".to_string(),
+    }
+}
+
+/// Type of an existing relation, `table` or `view`, which is also the keyword to drop it.
+/// SQLite refuses `DROP VIEW` on a table and the other way around.
+#[cfg(feature = "sqlite")]
+fn sqlite_relation_kind(
+    connection: &Connection,
+    name: &ObjectName,
+) -> Result<Option<String>, BackendError> {
+    connection
+        .query_row(
+            &format!("SELECT type FROM {} WHERE name = ?1", sqlite_master(name)),
+            params![name.0.last().unwrap().value],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(sqlite_error)
+}
+
+#[cfg(feature = "sqlite")]
+pub struct Sqlite {
+    // rusqlite blocks, the connection is used from the blocking thread pool
+    connection: Arc<Mutex<Connection>>,
+}
+
+#[cfg(feature = "sqlite")]
+impl Sqlite {
+    /// Runs a call with the connection on the blocking thread pool
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, BackendError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, BackendError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        blocking(move || f(&mut connection.lock().unwrap())).await
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[async_trait]
+impl Executor for Sqlite {
+    async fn new(profile: &Profile) -> Result<Sqlite, BackendError> {
+        let path = setting(&profile.path, "SQLITE_PATH")?;
+        let connection = Connection::open(&path).map_err(|e| BackendError::Connection {
+            message: format!("Failed to open {}: {}", path, e),
+        })?;
+
+        Ok(Sqlite {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn execute(&mut self, stmt: &mut Statement) -> Result<(), BackendError> {
+        let name = quoted(model_name(stmt));
+        // SQLite has no materialized views, a table is the closest equivalent
+        let sql = match &*stmt {
+            Statement::CreateView {
+                materialized: true,
+                query,
+                ..
+            } => format!("CREATE TABLE {name} AS {query}", name = name, query = query),
+            _ => format!("{}", stmt),
+        };
+
+        self.with_connection(move |connection| {
+            // SQLite supports transactional DDL, other connections see the old relation until commit
+            let transaction = connection.transaction().map_err(sqlite_error)?;
+
+            if let Some(kind) = sqlite_relation_kind(&transaction, &name)? {
+                transaction
+                    .execute_batch(format!("DROP {kind} {name}", kind = kind, name = name).as_str())
+                    .map_err(sqlite_error)?;
+            }
+
+            transaction
+                .execute_batch(sql.as_str())
+                .map_err(sqlite_error)?;
+
+            transaction.commit().map_err(sqlite_error)
+        })
+        .await
+    }
+
+    async fn execute_raw(&mut self, sql: &str) -> Result<(), BackendError> {
+        let sql = sql.to_string();
+        self.with_connection(move |connection| connection.execute_batch(&sql).map_err(sqlite_error))
+            .await
+    }
+
+    async fn query_bool(&mut self, query: &str) -> Result<bool, BackendError> {
+        let query = query.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .query_row(&query, NO_PARAMS, |row| row.get(0))
+                .map_err(sqlite_error)
+        })
+        .await
+    }
+
+    async fn execute_batch(&mut self, stmts: &[Statement]) -> Result<(), BackendError> {
+        let sql = join_statements(stmts);
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction().map_err(sqlite_error)?;
+
+            transaction
+                .execute_batch(sql.as_str())
+                .map_err(sqlite_error)?;
+
+            transaction.commit().map_err(sqlite_error)
+        })
+        .await
+    }
+
+    async fn table_exists(&mut self, name: &ObjectName) -> Result<bool, BackendError> {
+        let name = quoted(name);
+        self.with_connection(move |connection| {
+            let kind = sqlite_relation_kind(connection, &name)?;
+            Ok(kind.as_deref() == Some("table"))
+        })
+        .await
+    }
+
+    async fn get_schemas(&mut self) -> Result<Vec<TableSchema>, BackendError> {
+        self.with_connection(|connection| {
+            let mut stmt = connection
+                .prepare(
+                    "SELECT m.name, p.name, p.type FROM sqlite_master m \
+                     JOIN pragma_table_info(m.name) p \
+                     WHERE m.type IN ('table', 'view') AND m.name NOT LIKE 'sqlite_%' \
+                     ORDER BY m.name, p.cid",
+                )
+                .map_err(sqlite_error)?;
+            let rows = stmt
+                .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .map_err(sqlite_error)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(sqlite_error)?;
+
+            Ok(group_columns(rows))
+        })
+        .await
+    }
+}
+
+#[test]
+fn test_incremental_statements() {
+    let stmt = Parser::parse_sql(
+        &PowerSqlDialect {},
+        "CREATE TABLE orders AS SELECT id, amount FROM raw_orders",
+    )
+    .unwrap()
+    .remove(0);
+
+    let append = Incremental {
+        unique_key: None,
+        filter: None,
+    };
+    let stmts = append.statements(&stmt).unwrap();
+    assert_eq!(stmts.len(), 1);
+    assert_eq!(
+        stmts[0].to_string(),
+        "INSERT INTO orders SELECT * FROM (SELECT id, amount FROM raw_orders) AS powersql_new"
+    );
+
+    let merge = Incremental {
+        unique_key: Some("id".to_string()),
+        filter: None,
+    };
+    let stmts = merge.statements(&stmt).unwrap();
+    let stmts: Vec<_> = stmts.iter().map(|x| x.to_string()).collect();
+    assert_eq!(
+        stmts,
+        vec![
+            "DROP TABLE IF EXISTS orders__powersql_incremental",
+            "CREATE TABLE orders__powersql_incremental AS SELECT * FROM (SELECT id, amount FROM raw_orders) AS powersql_new",
+            "DELETE FROM orders WHERE id IN (SELECT id FROM orders__powersql_incremental)",
+            "INSERT INTO orders SELECT * FROM orders__powersql_incremental",
+            "DROP TABLE orders__powersql_incremental",
+        ]
+    );
+}
+
+#[test]
+fn test_with_name() {
+    let stmt = Parser::parse_sql(
+        &PowerSqlDialect {},
+        "CREATE MATERIALIZED VIEW revenue AS SELECT 1",
+    )
+    .unwrap()
+    .remove(0);
+
+    let renamed = with_name(
+        &stmt,
+        with_suffix(&quoted(model_name(&stmt)), "__powersql_new"),
+    );
+
+    assert_eq!(
+        renamed.to_string(),
+        "CREATE MATERIALIZED VIEW \"revenue__powersql_new\" AS SELECT 1"
+    );
+    assert_eq!(relation_kind(&renamed), "MATERIALIZED VIEW");
+}
+
+#[test]
+fn test_qualified_names() {
+    let stmt = Parser::parse_sql(
+        &PowerSqlDialect {},
+        "CREATE VIEW analytics.revenue AS SELECT 1",
+    )
+    .unwrap()
+    .remove(0);
+    let name = quoted(model_name(&stmt));
+
+    assert_eq!(name.to_string(), "\"analytics\".\"revenue\"");
+    assert_eq!(
+        with_suffix(&name, "__powersql_new").to_string(),
+        "\"analytics\".\"revenue__powersql_new\""
+    );
+    assert_eq!(
+        schema_of(&name).map(|x| x.to_string()),
+        Some("\"analytics\"".to_string())
+    );
+
+    // Temporary names of long names fit in a PostgreSQL identifier and stay distinct
+    let long = |last: &str| {
+        let mut name = name.clone();
+        name.0[1].value = last.to_string();
+        with_suffix(&name, "__powersql_new").0[1].value.clone()
+    };
+    let a = long(&"a".repeat(60));
+    let b = long(&format!("{}b", "a".repeat(59)));
+    assert_eq!(a.len(), MAX_IDENTIFIER_BYTES);
+    assert!(a.ends_with("__powersql_new"));
+    assert_ne!(a, b);
+    assert!(long(&"é".repeat(30)).len() <= MAX_IDENTIFIER_BYTES);
+
+    let stmt = Parser::parse_sql(&PowerSqlDialect {}, "CREATE VIEW revenue AS SELECT 1")
+        .unwrap()
+        .remove(0);
+    assert_eq!(schema_of(model_name(&stmt)), None);
+}
+
+#[test]
+fn test_group_columns() {
+    let rows = vec![
+        ("a".to_string(), "x".to_string(), "integer".to_string()),
+        ("a".to_string(), "y".to_string(), "text".to_string()),
+        ("b".to_string(), "z".to_string(), "date".to_string()),
+    ];
+
+    assert_eq!(
+        group_columns(rows),
+        vec![
+            TableSchema {
+                name: "a".to_string(),
+                columns: vec![
+                    ("x".to_string(), "integer".to_string()),
+                    ("y".to_string(), "text".to_string())
+                ],
+            },
+            TableSchema {
+                name: "b".to_string(),
+                columns: vec![("z".to_string(), "date".to_string())],
+            },
+        ]
+    );
+}
+
+#[cfg(feature = "postgres")]
+#[test]
+fn test_search_path_names() {
+    let row = |schema: &str, table: &str| {
+        (
+            schema.to_string(),
+            table.to_string(),
+            "id".to_string(),
+            "integer".to_string(),
+        )
+    };
+    // With the schema of a profile in front of the search path
+    let rows = vec![
+        row("dev_alice", "orders"),
+        row("other", "orders"),
+        row("public", "customers"),
+        row("public", "orders"),
+    ];
+    let search_path = vec!["dev_alice".to_string(), "public".to_string()];
+
+    let names: Vec<String> = search_path_names(rows, &search_path)
+        .into_iter()
+        .map(|(name, _, _)| name)
+        .collect();
+    assert_eq!(
+        names,
+        vec!["orders", "other.orders", "customers", "public.orders"]
+    );
+}
+
+#[cfg(feature = "bigquery")]
+#[test]
+fn test_bigquery_string() {
+    assert_eq!(bigquery_string("orders"), "'orders'");
+    assert_eq!(bigquery_string("o'rders\\"), "'o\\'rders\\\\'");
+}
+
+#[cfg(feature = "bigquery")]
+#[test]
+fn test_first_bool() {
+    let response = |values: &[Option<&str>]| {
+        let rows = values
+            .iter()
+            .map(|value| {
+                let mut cell = bigquery2::TableCell::default();
+                cell.v = value.map(|x| x.to_string());
+                let mut row = bigquery2::TableRow::default();
+                row.f = Some(vec![cell]);
+                row
+            })
+            .collect();
+        let mut response = QueryResponse::default();
+        response.rows = Some(rows);
+        response
+    };
+
+    assert_eq!(first_bool(&response(&[Some("true")])), Ok(true));
+    assert_eq!(first_bool(&response(&[Some("false")])), Ok(false));
+    assert!(first_bool(&response(&[None])).is_err());
+    assert!(first_bool(&response(&[Some("1.5")])).is_err());
+    assert!(first_bool(&response(&[])).is_err());
+    assert!(first_bool(&QueryResponse::default()).is_err());
+}
+
+// Runs against the database in PG_HOSTNAME, PG_USERNAME, ... when it is set
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_postgres_rebuild_keeps_settings() {
+    if env::var("PG_HOSTNAME").is_err() {
+        return;
+    }
+    let mut postgres = Postgres::new(&Profile::for_backend("postgres"))
+        .await
+        .unwrap();
+    let model = |sql: &str| {
+        Parser::parse_sql(&PowerSqlDialect {}, sql)
             .unwrap()
-            .parse::<bool>()
-            .unwrap())
+            .remove(0)
+    };
+    for sql in [
+        "DROP SCHEMA IF EXISTS powersql_test_settings CASCADE",
+        "DO $$ BEGIN CREATE ROLE powersql_test_reader; \
+         EXCEPTION WHEN duplicate_object THEN NULL; END $$",
+    ]
+    .iter()
+    {
+        postgres.execute_raw(sql).await.unwrap();
+    }
+    postgres
+        .execute(&mut model(
+            "CREATE TABLE powersql_test_settings.orders AS SELECT 1 AS id, 2 AS amount",
+        ))
+        .await
+        .unwrap();
+    for sql in [
+        "GRANT SELECT ON powersql_test_settings.orders TO powersql_test_reader",
+        "COMMENT ON TABLE powersql_test_settings.orders IS 'One row per order'",
+        "COMMENT ON COLUMN powersql_test_settings.orders.id IS 'Order id'",
+        "COMMENT ON COLUMN powersql_test_settings.orders.amount IS 'Removed'",
+        "CREATE VIEW powersql_test_settings.recent AS SELECT id FROM powersql_test_settings.orders",
+        "GRANT SELECT ON powersql_test_settings.recent TO powersql_test_reader",
+    ]
+    .iter()
+    {
+        postgres.execute_raw(sql).await.unwrap();
     }
+
+    // The table is replaced by a table without the amount column
+    postgres
+        .execute(&mut model(
+            "CREATE TABLE powersql_test_settings.orders AS SELECT 3 AS id",
+        ))
+        .await
+        .unwrap();
+
+    assert!(postgres
+        .query_bool(
+            "SELECT has_table_privilege('powersql_test_reader', 'powersql_test_settings.orders', 'SELECT') \
+             AND has_table_privilege('powersql_test_reader', 'powersql_test_settings.recent', 'SELECT') \
+             AND obj_description('powersql_test_settings.orders'::regclass, 'pg_class') = 'One row per order' \
+             AND col_description('powersql_test_settings.orders'::regclass, 1) = 'Order id'"
+        )
+        .await
+        .unwrap());
+    postgres
+        .execute_raw("DROP SCHEMA powersql_test_settings CASCADE")
+        .await
+        .unwrap();
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_incremental() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("powersql.db");
+    let profile = Profile {
+        path: Some(path.to_str().unwrap().to_string()),
+        ..Profile::for_backend("sqlite")
+    };
+    let mut sqlite = Sqlite::new(&profile).await.unwrap();
+    sqlite
+        .execute_raw(
+            "CREATE TABLE raw_orders (id INT, updated_at INT); \
+             INSERT INTO raw_orders VALUES (1, 10), (2, 20), (4, 15)",
+        )
+        .await
+        .unwrap();
+    let incremental = Incremental {
+        unique_key: Some("id".to_string()),
+        filter: Some(
+            Parser::new(
+                sqlparser::tokenizer::Tokenizer::new(
+                    &PowerSqlDialect {},
+                    "updated_at > (SELECT MAX(updated_at) FROM orders)",
+                )
+                .tokenize()
+                .unwrap(),
+            )
+            .parse_expr()
+            .unwrap(),
+        ),
+    };
+    let model = "CREATE TABLE orders AS SELECT id, updated_at FROM raw_orders";
+    let updates = [
+        "",
+        // Id 4 changes below the maximum of id 2, which drops to 15 once id 2 is deleted
+        "UPDATE raw_orders SET updated_at = 17 WHERE id = 4; \
+         UPDATE raw_orders SET updated_at = 30 WHERE id = 2",
+    ];
+
+    for update in updates.iter() {
+        if !update.is_empty() {
+            sqlite.execute_raw(update).await.unwrap();
+        }
+        let mut stmt = Parser::parse_sql(&PowerSqlDialect {}, model)
+            .unwrap()
+            .remove(0);
+        sqlite
+            .execute_incremental(&mut stmt, &incremental)
+            .await
+            .unwrap();
+    }
+
+    assert!(sqlite
+        .query_bool("SELECT COUNT(*) = 3 AND COUNT(DISTINCT id) = 3 FROM orders")
+        .await
+        .unwrap());
+    assert!(sqlite
+        .query_bool("SELECT updated_at = 30 FROM orders WHERE id = 2")
+        .await
+        .unwrap());
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_rerun() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("powersql.db");
+    let profile = Profile {
+        path: Some(path.to_str().unwrap().to_string()),
+        ..Profile::for_backend("sqlite")
+    };
+    let mut sqlite = Sqlite::new(&profile).await.unwrap();
+
+    // A second run replaces the table, also when the model changes between table and view
+    let models = [
+        "CREATE TABLE orders AS SELECT 1 AS id",
+        "CREATE TABLE orders AS SELECT 2 AS id",
+        "CREATE VIEW orders AS SELECT 3 AS id",
+        "CREATE TABLE orders AS SELECT 4 AS id",
+    ];
+    for sql in models.iter() {
+        let mut stmt = Parser::parse_sql(&PowerSqlDialect {}, sql)
+            .unwrap()
+            .remove(0);
+        sqlite.execute(&mut stmt).await.unwrap();
+    }
+
+    let name = ObjectName(vec![Ident {
+        value: "orders".to_string(),
+        quote_style: None,
+    }]);
+    assert!(sqlite.table_exists(&name).await.unwrap());
+    assert!(sqlite
+        .query_bool("SELECT id = 4 FROM orders")
+        .await
+        .unwrap());
 }
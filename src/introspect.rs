@@ -0,0 +1,112 @@
+use super::execute::TableSchema;
+
+/// Translates a column type reported by the database into a type
+/// that can be parsed in a `CREATE TABLE` statement
+pub fn normalize_type(data_type: &str) -> String {
+    let data_type = data_type.trim().to_uppercase();
+
+    if data_type.starts_with("ARRAY<") && data_type.ends_with('>') {
+        return format!("{}[]", normalize_type(&data_type[6..data_type.len() - 1]));
+    }
+    if data_type.starts_with("STRUCT<") {
+        return "STRUCT".to_string();
+    }
+    if data_type.starts_with("NUMERIC") || data_type.starts_with("DECIMAL") {
+        return data_type.replacen("NUMERIC", "DECIMAL", 1);
+    }
+    if data_type.starts_with("TIMESTAMP") || data_type == "DATETIME" {
+        return "TIMESTAMP".to_string();
+    }
+    if data_type.starts_with("TIME") {
+        return "TIME".to_string();
+    }
+    if data_type.starts_with("CHARACTER") || data_type.starts_with("VARCHAR") {
+        return "TEXT".to_string();
+    }
+
+    let normalized = match data_type.as_str() {
+        "INT64" | "INTEGER" | "INT" | "INT4" => "INT",
+        "BIGINT" | "INT8" => "BIGINT",
+        "SMALLINT" | "INT2" => "SMALLINT",
+        "FLOAT64" | "FLOAT" | "FLOAT8" | "DOUBLE PRECISION" => "DOUBLE",
+        "REAL" | "FLOAT4" => "REAL",
+        "BOOL" | "BOOLEAN" => "BOOLEAN",
+        "STRING" | "TEXT" | "CHAR" | "BPCHAR" => "TEXT",
+        "DATE" => "DATE",
+        "INTERVAL" => "INTERVAL",
+        "BYTES" | "BYTEA" | "BLOB" => "BYTEA",
+        "JSON" | "JSONB" => "JSON",
+        // PostgreSQL reports arrays without the element type
+        "ARRAY" => "UNKNOWN[]",
+        x if !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+            return x.to_string()
+        }
+        _ => "UNKNOWN",
+    };
+    normalized.to_string()
+}
+
+fn quote_ident(ident: &str) -> String {
+    let simple = ident
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_ascii_lowercase() || c == '_')
+        && ident
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if simple {
+        ident.to_string()
+    } else {
+        format!("\"{}\"", ident.replace('"', "\"\""))
+    }
+}
+
+/// Source declarations for the tables, in the format read by `powersql check`
+pub fn sources_ddl(tables: &[TableSchema]) -> String {
+    let mut res = String::new();
+    for table in tables {
+        let name = table
+            .name
+            .split('.')
+            .map(quote_ident)
+            .collect::<Vec<_>>()
+            .join(".");
+        let columns = table
+            .columns
+            .iter()
+            .map(|(column, data_type)| {
+                format!("    {} {}", quote_ident(column), normalize_type(data_type))
+            })
+            .collect::<Vec<_>>()
+            .join(",\n");
+        res.push_str(&format!("CREATE TABLE {} (\n{}\n);\n", name, columns));
+    }
+    res
+}
+
+#[test]
+fn test_normalize_type() {
+    assert_eq!(normalize_type("character varying"), "TEXT");
+    assert_eq!(normalize_type("INT64"), "INT");
+    assert_eq!(normalize_type("ARRAY<STRING>"), "TEXT[]");
+    assert_eq!(normalize_type("numeric(10,2)"), "DECIMAL(10,2)");
+    assert_eq!(normalize_type("timestamp without time zone"), "TIMESTAMP");
+    assert_eq!(normalize_type("USER-DEFINED"), "UNKNOWN");
+    assert_eq!(normalize_type(""), "UNKNOWN");
+}
+
+#[test]
+fn test_sources_ddl() {
+    let tables = vec![TableSchema {
+        name: "raw.orders".to_string(),
+        columns: vec![
+            ("id".to_string(), "integer".to_string()),
+            ("Order Date".to_string(), "date".to_string()),
+        ],
+    }];
+
+    assert_eq!(
+        sources_ddl(&tables),
+        "CREATE TABLE raw.orders (\n    id INT,\n    \"Order Date\" DATE\n);\n"
+    );
+}
@@ -0,0 +1,524 @@
+use super::error::PowerSqlError;
+use super::tables::get_query;
+use serde_derive::Serialize;
+use sqlparser::ast::{Expr, Query, SelectItem, SetExpr, Statement, TableFactor};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// A column of a model or source
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct ColumnRef {
+    pub table: String,
+    pub column: String,
+}
+
+impl fmt::Display for ColumnRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.table, self.column)
+    }
+}
+
+/// For every output column the upstream columns it is derived from
+pub type ColumnLineage = BTreeMap<String, BTreeSet<ColumnRef>>;
+
+// Columns of a query in order, unnamed expressions have no name
+type Columns = Vec<(Option<String>, BTreeSet<ColumnRef>)>;
+
+enum Relation {
+    // Model or source, with the columns if they are known
+    Table(String, Option<Vec<String>>),
+    // Subquery or CTE, with the upstream columns of its columns
+    Derived(ColumnLineage),
+}
+
+impl Relation {
+    fn has_column(&self, column: &str) -> bool {
+        match self {
+            Relation::Table(_, Some(columns)) => columns.iter().any(|x| x == column),
+            Relation::Table(_, None) => false,
+            Relation::Derived(lineage) => lineage.contains_key(column),
+        }
+    }
+
+    fn column(&self, column: &str) -> BTreeSet<ColumnRef> {
+        match self {
+            Relation::Table(table, _) => {
+                let mut res = BTreeSet::new();
+                res.insert(ColumnRef {
+                    table: table.clone(),
+                    column: column.to_string(),
+                });
+                res
+            }
+            Relation::Derived(lineage) => lineage.get(column).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// All known columns, for `SELECT *`
+    fn columns(&self) -> Columns {
+        match self {
+            Relation::Table(_, Some(columns)) => columns
+                .iter()
+                .map(|x| (Some(x.clone()), self.column(x)))
+                .collect(),
+            Relation::Table(_, None) => vec![],
+            Relation::Derived(lineage) => lineage
+                .iter()
+                .map(|(x, refs)| (Some(x.clone()), refs.clone()))
+                .collect(),
+        }
+    }
+}
+
+struct Scope<'a> {
+    tables: &'a HashMap<String, Vec<String>>,
+    ctes: im::HashMap<String, ColumnLineage>,
+    // Relations in the FROM clause by alias or name
+    relations: Vec<(String, Relation)>,
+}
+
+impl<'a> Scope<'a> {
+    fn relation(&self, name: &str) -> Option<&Relation> {
+        self.relations
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, relation)| relation)
+    }
+
+    fn add_table_factor(&mut self, table_factor: &TableFactor) {
+        match table_factor {
+            TableFactor::Table { name, alias, .. } => {
+                let table = name.to_string();
+                let local_name = match alias {
+                    Some(alias) => alias.name.value.clone(),
+                    None => name.0.last().map(|x| x.value.clone()).unwrap_or_default(),
+                };
+                let relation = match self.ctes.get(&table) {
+                    Some(lineage) => Relation::Derived(lineage.clone()),
+                    None => Relation::Table(table.clone(), self.tables.get(&table).cloned()),
+                };
+                self.relations.push((local_name, relation));
+            }
+            TableFactor::Derived {
+                subquery, alias, ..
+            } => {
+                let lineage = to_lineage(query_columns(subquery, self.tables, &self.ctes));
+                let name = alias
+                    .as_ref()
+                    .map(|x| x.name.value.clone())
+                    .unwrap_or_default();
+                self.relations.push((name, Relation::Derived(lineage)));
+            }
+            TableFactor::NestedJoin(join) => {
+                self.add_table_factor(&join.relation);
+                for j in join.joins.iter() {
+                    self.add_table_factor(&j.relation);
+                }
+            }
+        }
+    }
+
+    /// Upstream columns of a column reference like `a`, `t.a` or `schema.t.a`
+    fn resolve(&self, idents: &[String]) -> BTreeSet<ColumnRef> {
+        match idents {
+            [table, column, ..] if self.relation(table).is_some() => {
+                self.relation(table).unwrap().column(column)
+            }
+            [_schema, table, column, ..] if self.relation(table).is_some() => {
+                self.relation(table).unwrap().column(column)
+            }
+            // Column, or a field of a struct column
+            [column, ..] => {
+                let found: Vec<_> = self
+                    .relations
+                    .iter()
+                    .filter(|(_, relation)| relation.has_column(column))
+                    .collect();
+                let candidates: Vec<_> = if !found.is_empty() {
+                    found
+                } else if self.relations.len() == 1 {
+                    self.relations.iter().collect()
+                } else {
+                    // Could be any of the tables with unknown columns
+                    self.relations
+                        .iter()
+                        .filter(|(_, relation)| match relation {
+                            Relation::Table(_, None) => true,
+                            _ => false,
+                        })
+                        .collect()
+                };
+                candidates
+                    .iter()
+                    .flat_map(|(_, relation)| relation.column(column))
+                    .collect()
+            }
+            [] => BTreeSet::new(),
+        }
+    }
+
+    fn expr_refs(&self, expr: &Expr, res: &mut BTreeSet<ColumnRef>) {
+        match expr {
+            Expr::Identifier(id) => res.extend(self.resolve(&[id.value.clone()])),
+            Expr::CompoundIdentifier(ids) => {
+                let idents: Vec<_> = ids.iter().map(|x| x.value.clone()).collect();
+                res.extend(self.resolve(&idents))
+            }
+            Expr::BinaryOp { left, right, .. } => {
+                self.expr_refs(left, res);
+                self.expr_refs(right, res);
+            }
+            Expr::UnaryOp { expr, .. }
+            | Expr::Nested(expr)
+            | Expr::Cast { expr, .. }
+            | Expr::Extract { expr, .. }
+            | Expr::Collate { expr, .. } => self.expr_refs(expr, res),
+            Expr::Function(function) => {
+                for arg in function.args.iter() {
+                    self.expr_refs(arg, res);
+                }
+            }
+            Expr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => {
+                for expr in operand
+                    .iter()
+                    .chain(else_result.iter())
+                    .map(|x| x.as_ref())
+                    .chain(conditions.iter())
+                    .chain(results.iter())
+                {
+                    self.expr_refs(expr, res);
+                }
+            }
+            // The value of a scalar subquery comes from its columns
+            Expr::Subquery(query) => {
+                for (_, refs) in query_columns(query, self.tables, &self.ctes) {
+                    res.extend(refs);
+                }
+            }
+            // Filters like IS NULL, IN and EXISTS don't pass on values
+            _ => {}
+        }
+    }
+}
+
+fn to_lineage(columns: Columns) -> ColumnLineage {
+    columns
+        .into_iter()
+        .filter_map(|(name, refs)| name.map(|name| (name, refs)))
+        .collect()
+}
+
+fn set_expr_columns(
+    body: &SetExpr,
+    tables: &HashMap<String, Vec<String>>,
+    ctes: &im::HashMap<String, ColumnLineage>,
+) -> Columns {
+    match body {
+        SetExpr::Select(select) => {
+            let mut scope = Scope {
+                tables,
+                ctes: ctes.clone(),
+                relations: vec![],
+            };
+            for table in select.from.iter() {
+                scope.add_table_factor(&table.relation);
+                for join in table.joins.iter() {
+                    scope.add_table_factor(&join.relation);
+                }
+            }
+            let mut res = vec![];
+            for item in select.projection.iter() {
+                match item {
+                    SelectItem::UnnamedExpr(expr) => {
+                        let name = match expr {
+                            Expr::Identifier(id) => Some(id.to_string()),
+                            Expr::CompoundIdentifier(ids) => ids.last().map(|x| x.to_string()),
+                            _ => None,
+                        };
+                        let mut refs = BTreeSet::new();
+                        scope.expr_refs(expr, &mut refs);
+                        res.push((name, refs));
+                    }
+                    SelectItem::ExprWithAlias { expr, alias } => {
+                        let mut refs = BTreeSet::new();
+                        scope.expr_refs(expr, &mut refs);
+                        res.push((Some(alias.to_string()), refs));
+                    }
+                    SelectItem::Wildcard => {
+                        for (_, relation) in scope.relations.iter() {
+                            res.extend(relation.columns());
+                        }
+                    }
+                    SelectItem::QualifiedWildcard(name) => {
+                        let table = name.0.last().map(|x| x.value.as_str()).unwrap_or_default();
+                        if let Some(relation) = scope.relation(table) {
+                            res.extend(relation.columns());
+                        }
+                    }
+                }
+            }
+            res
+        }
+        SetExpr::Query(query) => query_columns(query, tables, ctes),
+        // Columns are combined by position, with the names of the left query
+        SetExpr::SetOperation { left, right, .. } => {
+            let mut res = set_expr_columns(left, tables, ctes);
+            let right = set_expr_columns(right, tables, ctes);
+            for ((_, refs), (_, other)) in res.iter_mut().zip(right.into_iter()) {
+                refs.extend(other);
+            }
+            res
+        }
+        SetExpr::Values(_) => vec![],
+    }
+}
+
+fn query_columns(
+    query: &Query,
+    tables: &HashMap<String, Vec<String>>,
+    ctes: &im::HashMap<String, ColumnLineage>,
+) -> Columns {
+    let mut ctes = ctes.clone();
+    for cte in query.ctes.iter() {
+        let lineage = to_lineage(query_columns(&cte.query, tables, &ctes));
+        ctes.insert(cte.alias.name.value.clone(), lineage);
+    }
+    set_expr_columns(&query.body, tables, &ctes)
+}
+
+/// Lineage of the columns of a model, `tables` has the known columns of models and sources
+pub fn model_lineage(query: &Query, tables: &HashMap<String, Vec<String>>) -> ColumnLineage {
+    to_lineage(query_columns(query, tables, &im::HashMap::new()))
+}
+
+/// Lineage of every model, models are processed after the models they depend on
+/// so `SELECT *` can be expanded
+pub fn lineage_graph(
+    asts: &HashMap<String, Statement>,
+    dependencies: &HashMap<String, Vec<String>>,
+    mut tables: HashMap<String, Vec<String>>,
+) -> BTreeMap<String, ColumnLineage> {
+    let mut res = BTreeMap::new();
+    let mut remaining: Vec<&String> = dependencies.keys().collect();
+    remaining.sort();
+    while !remaining.is_empty() {
+        let (ready, waiting): (Vec<&String>, Vec<&String>) =
+            remaining.iter().copied().partition(|model| {
+                dependencies[*model]
+                    .iter()
+                    .all(|dep| res.contains_key(dep) || !dependencies.contains_key(dep))
+            });
+        // Cycles are reported before, process the rest anyway
+        let ready = if ready.is_empty() {
+            waiting.clone()
+        } else {
+            ready
+        };
+        for model in ready.iter() {
+            let lineage = model_lineage(get_query(&asts[*model]), &tables);
+            tables.insert(model.to_string(), lineage.keys().cloned().collect());
+            res.insert(model.to_string(), lineage);
+        }
+        remaining = remaining
+            .into_iter()
+            .filter(|x| !res.contains_key(*x))
+            .collect();
+    }
+    res
+}
+
+/// Columns that depend on the column, directly or through other models
+pub fn downstream(graph: &BTreeMap<String, ColumnLineage>, column: &ColumnRef) -> Vec<ColumnRef> {
+    let mut res: Vec<ColumnRef> = vec![];
+    let mut todo = vec![column.clone()];
+    while let Some(current) = todo.pop() {
+        for (model, lineage) in graph.iter() {
+            for (name, refs) in lineage.iter() {
+                let dependent = ColumnRef {
+                    table: model.clone(),
+                    column: name.clone(),
+                };
+                if refs.contains(&current) && !res.contains(&dependent) {
+                    res.push(dependent.clone());
+                    todo.push(dependent);
+                }
+            }
+        }
+    }
+    res.sort();
+    res
+}
+
+/// Upstream columns as an indented tree, following the models up to the sources
+pub fn upstream_tree(graph: &BTreeMap<String, ColumnLineage>, column: &ColumnRef) -> String {
+    fn visit(
+        graph: &BTreeMap<String, ColumnLineage>,
+        column: &ColumnRef,
+        depth: usize,
+        res: &mut String,
+    ) {
+        res.push_str(&format!("{}{}\n", "  ".repeat(depth), column));
+        // Columns of sources are not expanded
+        if let Some(refs) = graph.get(&column.table).and_then(|x| x.get(&column.column)) {
+            for r in refs.iter() {
+                visit(graph, r, depth + 1, res);
+            }
+        }
+    }
+    let mut res = String::new();
+    visit(graph, column, 0, &mut res);
+    res
+}
+
+/// Parses `model.column`
+pub fn parse_column(name: &str) -> Option<ColumnRef> {
+    let position = name.rfind('.')?;
+    Some(ColumnRef {
+        table: name[..position].to_string(),
+        column: name[position + 1..].to_string(),
+    })
+}
+
+/// Checks that the column belongs to a model in the graph or a source in `sources`
+pub fn check_column(
+    graph: &BTreeMap<String, ColumnLineage>,
+    sources: &HashMap<String, Vec<String>>,
+    column: &ColumnRef,
+) -> Result<(), PowerSqlError> {
+    let found = match (graph.get(&column.table), sources.get(&column.table)) {
+        (Some(model), _) => model.contains_key(&column.column),
+        (None, Some(columns)) => columns.contains(&column.column),
+        (None, None) => {
+            return Err(PowerSqlError::config(format!(
+                "Table {} is neither a model nor a declared source",
+                column.table
+            )))
+        }
+    };
+    if !found {
+        return Err(PowerSqlError::config(format!(
+            "Column {} not found in {}",
+            column.column, column.table
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+use super::parser::PowerSqlDialect;
+#[cfg(test)]
+use sqlparser::parser::Parser;
+
+#[test]
+fn test_model_lineage() {
+    let sql = "WITH paid AS (SELECT id, amount * 1.21 AS total FROM orders WHERE paid) \
+               SELECT c.name, p.total, CASE WHEN p.total > 100 THEN c.segment END AS segment, \
+               c.* FROM paid p JOIN customers c ON p.id = c.order_id";
+    let query = match &Parser::parse_sql(&PowerSqlDialect {}, sql).unwrap()[0] {
+        Statement::Query(query) => query.clone(),
+        _ => unreachable!(),
+    };
+    let tables = hashmap! {
+        "customers".to_string() => vec!["name".to_string(), "country".to_string()],
+    };
+    let column = |table: &str, column: &str| ColumnRef {
+        table: table.to_string(),
+        column: column.to_string(),
+    };
+
+    let lineage = model_lineage(&query, &tables);
+
+    assert_eq!(
+        lineage.get("total"),
+        Some(&vec![column("orders", "amount")].into_iter().collect())
+    );
+    assert_eq!(
+        lineage.get("segment"),
+        Some(
+            &vec![column("customers", "segment"), column("orders", "amount")]
+                .into_iter()
+                .collect()
+        )
+    );
+    assert_eq!(
+        lineage.get("country"),
+        Some(&vec![column("customers", "country")].into_iter().collect())
+    );
+}
+
+#[test]
+fn test_downstream() {
+    let column = |table: &str, column: &str| ColumnRef {
+        table: table.to_string(),
+        column: column.to_string(),
+    };
+    let graph: BTreeMap<String, ColumnLineage> = vec![
+        (
+            "a".to_string(),
+            vec![(
+                "x".to_string(),
+                vec![column("raw", "x")].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+        ),
+        (
+            "b".to_string(),
+            vec![(
+                "y".to_string(),
+                vec![column("a", "x")].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    assert_eq!(
+        downstream(&graph, &column("raw", "x")),
+        vec![column("a", "x"), column("b", "y")]
+    );
+    assert_eq!(
+        upstream_tree(&graph, &column("b", "y")),
+        "b.y\n  a.x\n    raw.x\n"
+    );
+}
+
+#[test]
+fn test_check_column() {
+    let column = |table: &str, column: &str| ColumnRef {
+        table: table.to_string(),
+        column: column.to_string(),
+    };
+    let graph: BTreeMap<String, ColumnLineage> = vec![(
+        "a".to_string(),
+        vec![(
+            "x".to_string(),
+            vec![column("raw", "x")].into_iter().collect(),
+        )]
+        .into_iter()
+        .collect(),
+    )]
+    .into_iter()
+    .collect();
+    let sources = hashmap! {
+        "raw".to_string() => vec!["x".to_string()],
+    };
+
+    assert!(check_column(&graph, &sources, &column("a", "x")).is_ok());
+    assert!(check_column(&graph, &sources, &column("raw", "x")).is_ok());
+    assert!(check_column(&graph, &sources, &column("a", "y")).is_err());
+    assert!(check_column(&graph, &sources, &column("raw", "y")).is_err());
+    assert_eq!(
+        check_column(&graph, &sources, &column("typo", "x")),
+        Err(PowerSqlError::config(
+            "Table typo is neither a model nor a declared source"
+        ))
+    );
+}
@@ -0,0 +1,279 @@
+use super::error::PowerSqlError;
+use super::template::position;
+
+/// Macro defined with `CREATE MACRO name(param, ...) AS body;` in a macros directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub params: Vec<String>,
+    pub body: String,
+}
+
+// Names of the template functions, which can't be used for macros
+const RESERVED: &[&str] = &["var", "env_var", "ref"];
+
+struct Cursor<'a> {
+    file: &'a str,
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn error(&self, message: impl Into<String>) -> PowerSqlError {
+        let (line, column) = position(self.source, self.pos);
+        PowerSqlError::Parse {
+            file: self.file.to_string(),
+            line: Some(line),
+            column: Some(column),
+            message: message.into(),
+        }
+    }
+
+    /// Skips whitespace and `--` comments
+    fn skip_space(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if !trimmed.starts_with("--") {
+                return;
+            }
+            self.pos += trimmed.find('\n').unwrap_or_else(|| trimmed.len());
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, PowerSqlError> {
+        self.skip_space();
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or_else(|| rest.len());
+        if end == 0 || rest.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.error("Expected a name"));
+        }
+        self.pos += end;
+        Ok(&rest[..end])
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), PowerSqlError> {
+        let start = self.pos;
+        match self.name() {
+            Ok(name) if name.eq_ignore_ascii_case(keyword) => Ok(()),
+            _ => {
+                self.pos = start;
+                self.skip_space();
+                Err(self.error(format!("Expected {}", keyword)))
+            }
+        }
+    }
+
+    fn symbol(&mut self, symbol: char) -> Result<(), PowerSqlError> {
+        self.skip_space();
+        if self.rest().starts_with(symbol) {
+            self.pos += symbol.len_utf8();
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected {}", symbol)))
+        }
+    }
+
+    /// Text up to the next `;` outside of strings and quoted identifiers
+    fn statement(&mut self) -> &'a str {
+        let rest = self.rest();
+        let mut quote = None;
+        let mut end = rest.len();
+        for (i, c) in rest.char_indices() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if c == '\'' || c == '"' => quote = Some(c),
+                None if c == ';' => {
+                    end = i;
+                    break;
+                }
+                None => {}
+            }
+        }
+        self.pos += (end + 1).min(rest.len());
+        rest[..end].trim()
+    }
+}
+
+/// Parses the macro definitions in a file
+pub fn parse_macros(file: &str, source: &str) -> Result<Vec<(String, Macro)>, PowerSqlError> {
+    let mut cursor = Cursor {
+        file,
+        source,
+        pos: 0,
+    };
+    let mut res = vec![];
+    loop {
+        cursor.skip_space();
+        if cursor.rest().is_empty() {
+            return Ok(res);
+        }
+        cursor.keyword("CREATE")?;
+        cursor.keyword("MACRO")?;
+        let start = cursor.pos;
+        let name = cursor.name()?;
+        if RESERVED.contains(&name) {
+            cursor.pos = start;
+            cursor.skip_space();
+            return Err(cursor.error(format!("{} is a template function, not a macro", name)));
+        }
+
+        cursor.symbol('(')?;
+        let mut params = vec![];
+        if cursor.symbol(')').is_err() {
+            loop {
+                params.push(cursor.name()?.to_string());
+                if cursor.symbol(',').is_err() {
+                    cursor.symbol(')')?;
+                    break;
+                }
+            }
+        }
+        cursor.keyword("AS")?;
+        let body = cursor.statement().to_string();
+        res.push((name.to_string(), Macro { params, body }));
+    }
+}
+
+/// Argument as inserted in the body, in parentheses unless it's a name or a literal
+fn atom(arg: &str) -> String {
+    let name = arg
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '.');
+    let string = arg.len() >= 2
+        && arg.starts_with('\'')
+        && arg.ends_with('\'')
+        && !arg[1..arg.len() - 1].contains('\'');
+    if name || string {
+        arg.to_string()
+    } else {
+        format!("({})", arg)
+    }
+}
+
+impl Macro {
+    /// Body with the parameters replaced by the arguments. Parameters are only replaced
+    /// outside of strings and quoted identifiers and not after a `.`, so in
+    /// `r.currency = currency` only the second `currency` is a parameter.
+    pub fn substitute(&self, args: &[&str]) -> String {
+        let body = self.body.as_str();
+        let mut res = String::new();
+        let mut previous = None;
+        let mut chars = body.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if c == '\'' || c == '"' {
+                res.push(c);
+                for (_, x) in chars.by_ref() {
+                    res.push(x);
+                    if x == c {
+                        break;
+                    }
+                }
+                previous = Some(c);
+                continue;
+            }
+            if !(c.is_alphanumeric() || c == '_') {
+                res.push(c);
+                previous = Some(c);
+                continue;
+            }
+
+            let mut end = start + c.len_utf8();
+            while let Some(&(i, x)) = chars.peek() {
+                if !(x.is_alphanumeric() || x == '_') {
+                    break;
+                }
+                end = i + x.len_utf8();
+                chars.next();
+            }
+            let word = &body[start..end];
+            match self.params.iter().position(|x| x == word) {
+                Some(i) if previous != Some('.') && !c.is_ascii_digit() => {
+                    res.push_str(&atom(args[i]))
+                }
+                _ => res.push_str(word),
+            }
+            previous = word.chars().last();
+        }
+        res
+    }
+}
+
+#[test]
+fn test_parse_macros() {
+    let source = "-- Currency conversion\n\
+                  CREATE MACRO to_eur(amount, currency) AS amount * rate(currency);\n\
+                  create macro week_start(d) as DATE_TRUNC('week', d);\n\
+                  CREATE MACRO answer() AS 42";
+
+    assert_eq!(
+        parse_macros("macros.sql", source).unwrap(),
+        vec![
+            (
+                "to_eur".to_string(),
+                Macro {
+                    params: vec!["amount".to_string(), "currency".to_string()],
+                    body: "amount * rate(currency)".to_string(),
+                }
+            ),
+            (
+                "week_start".to_string(),
+                Macro {
+                    params: vec!["d".to_string()],
+                    body: "DATE_TRUNC('week', d)".to_string(),
+                }
+            ),
+            (
+                "answer".to_string(),
+                Macro {
+                    params: vec![],
+                    body: "42".to_string(),
+                }
+            ),
+        ]
+    );
+
+    assert_eq!(
+        parse_macros("macros.sql", "CREATE MACRO f(a b) AS a"),
+        Err(PowerSqlError::Parse {
+            file: "macros.sql".to_string(),
+            line: Some(1),
+            column: Some(18),
+            message: "Expected )".to_string(),
+        })
+    );
+    assert_eq!(
+        parse_macros("macros.sql", "\nCREATE MACRO ref(a) AS a"),
+        Err(PowerSqlError::Parse {
+            file: "macros.sql".to_string(),
+            line: Some(2),
+            column: Some(14),
+            message: "ref is a template function, not a macro".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_substitute() {
+    let m = Macro {
+        params: vec!["amount".to_string(), "currency".to_string()],
+        body: "amount * (SELECT rate FROM rates r WHERE r.currency = currency AND \"amount\" > 'amount')"
+            .to_string(),
+    };
+
+    assert_eq!(
+        m.substitute(&["price", "'USD'"]),
+        "price * (SELECT rate FROM rates r WHERE r.currency = 'USD' AND \"amount\" > 'amount')"
+    );
+    assert_eq!(
+        m.substitute(&["a + b", "o.currency"]),
+        "(a + b) * (SELECT rate FROM rates r WHERE r.currency = o.currency AND \"amount\" > 'amount')"
+    );
+}
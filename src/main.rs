@@ -1,40 +1,131 @@
+mod error;
 mod execute;
+mod introspect;
+mod lineage;
+mod macros;
 mod parser;
+mod profile;
+mod select;
+mod tables;
+mod template;
 mod types;
-use execute::Executor;
+use error::{PowerSqlError, Warning};
+use execute::{BackendError, Executor, Incremental};
 use parser::PowerSqlDialect;
+use profile::Profile;
 use serde_derive::Deserialize;
-use sqlparser::ast::{
-    Cte, Expr, Function, ListAgg, Query, SelectItem, SetExpr, Statement, TableFactor, Value,
-};
-use sqlparser::parser::Parser;
+use sqlparser::ast::{Expr, Ident, ObjectName, Query, Statement, Value};
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::Tokenizer;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
 use structopt::StructOpt;
+use tables::{get_query, get_refs, get_refs_expr, unquoted_name, visit_tables_query};
+use tokio::sync::mpsc;
 use walkdir::WalkDir;
 
 #[derive(Deserialize, Debug)]
 struct PowerSqlConfig {
     project: Project,
+    target: Option<Target>,
+    #[serde(default)]
+    models: HashMap<String, ModelConfig>,
+    #[serde(default)]
+    directories: HashMap<String, DirectoryConfig>,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+    // Variables used in templates with var('name')
+    #[serde(default)]
+    vars: HashMap<String, toml::Value>,
 }
 #[derive(Deserialize, Debug)]
 struct Project {
     name: String,
     models: Vec<String>,
     tests: Option<Vec<String>>,
+    sources: Option<Vec<String>>,
+    macros: Option<Vec<String>>,
+    threads: Option<usize>,
+    // SQL run before the first and after the last model of `powersql run`
+    on_run_start: Option<Vec<String>>,
+    on_run_end: Option<Vec<String>>,
 }
+#[derive(Deserialize, Debug)]
+struct ModelConfig {
+    materialized: Option<String>,
+    unique_key: Option<String>,
+    filter: Option<String>,
+    pre_hook: Option<Vec<String>>,
+    post_hook: Option<Vec<String>>,
+}
+#[derive(Deserialize, Debug)]
+struct DirectoryConfig {
+    // Schema (dataset in BigQuery) of the models in the directory named without schema
+    schema: Option<String>,
+    // Materialization of the SELECT models in the directory
+    materialized: Option<String>,
+}
+#[derive(Deserialize, Debug)]
+struct Target {
+    backend: Option<String>,
+    // Profile used without --target
+    profile: Option<String>,
+}
+#[derive(Debug, StructOpt)]
+struct Selection {
+    /// Models to include: `model`, `+model` (with ancestors), `model+` (with descendants) or `path:dir`
+    #[structopt(long)]
+    select: Vec<String>,
+    /// Models to leave out, using the same syntax as --select
+    #[structopt(long)]
+    exclude: Vec<String>,
+}
+
 #[derive(Debug, StructOpt)]
 enum Command {
-    Check,
-    Run,
+    Check {
+        #[structopt(flatten)]
+        selection: Selection,
+    },
+    Run {
+        /// Maximum number of models executed concurrently
+        #[structopt(long)]
+        threads: Option<usize>,
+        /// Rebuild incremental models from scratch
+        #[structopt(long)]
+        full_refresh: bool,
+        #[structopt(flatten)]
+        selection: Selection,
+    },
     Test {
         #[structopt(long)]
         fail_fast: bool,
+        #[structopt(flatten)]
+        selection: Selection,
     },
     Docs,
+    /// Shows the columns a column is derived from
+    Lineage {
+        /// Column as model.column
+        column: Option<String>,
+        /// Shows the columns derived from the column instead
+        #[structopt(long)]
+        downstream: bool,
+        /// Writes the lineage of all columns as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Writes the tables in the database as source declarations
+    Introspect {
+        /// Defaults to introspected.sql in the first sources directory
+        #[structopt(long)]
+        output: Option<String>,
+    },
 }
 
 struct Test {
@@ -42,162 +133,582 @@ struct Test {
     message: String,
 }
 
+/// SQL run before and after building a model
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Hooks {
+    pre: Vec<String>,
+    post: Vec<String>,
+}
+
+/// Models loaded from the model files
+#[derive(Debug)]
+struct Models {
+    asts: HashMap<String, Statement>,
+    // File of every model
+    paths: HashMap<String, String>,
+    hooks: HashMap<String, Hooks>,
+    warnings: Vec<Warning>,
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "PowerSQL", about = "The data tool")]
 struct Opt {
+    /// Profile from [profiles] or backend (postgres, bigquery or sqlite) to run against,
+    /// overrides [target] in powersql.toml
+    #[structopt(long, global = true)]
+    target: Option<String>,
+    /// Template variable as name=value, overrides [vars] in powersql.toml
+    #[structopt(long, global = true)]
+    vars: Vec<String>,
     #[structopt(subcommand, name = "CMD")]
     command: Command,
 }
 
-// TODO don't pass mutable vec
-fn get_refs_cte(cte: &Cte, vec: &mut Vec<String>) {
-    get_refs(&cte.query, vec)
+fn read_file(path: &str) -> Result<String, PowerSqlError> {
+    fs::read_to_string(path).map_err(|err| PowerSqlError::Io {
+        path: path.to_string(),
+        message: format!("{}", err),
+    })
 }
 
-fn get_refs_table_factor(table_factor: &TableFactor, vec: &mut Vec<String>) {
-    match table_factor {
-        TableFactor::Table { name, .. } => vec.push(format!("{}", name)),
-        TableFactor::NestedJoin(nested_join) => {
-            get_refs_table_factor(&nested_join.relation, vec);
+/// A statement of a model or test file
+enum FileStatement {
+    Parsed(Statement),
+    // Statements the parser doesn't support, like GRANT or COMMENT ON, as written
+    Raw(String),
+}
 
-            for join in nested_join.joins.iter() {
-                get_refs_table_factor(&join.relation, vec);
-            }
-        }
-        TableFactor::Derived {
-            subquery: query, ..
-        } => {
-            get_refs(query, vec);
+impl fmt::Display for FileStatement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileStatement::Parsed(statement) => write!(f, "{}", statement),
+            FileStatement::Raw(sql) => write!(f, "{}", sql),
         }
     }
 }
 
-fn get_refs_set_expr(body: &SetExpr, vec: &mut Vec<String>) {
-    match body {
-        SetExpr::Query(q) => get_refs(q, vec),
-        SetExpr::Select(select) => {
-            select
-                .from
-                .iter()
-                .for_each(|table| get_refs_table_factor(&table.relation, vec));
+/// Whether a statement is meant as a model or test, so it should be parsed
+fn is_model_statement(sql: &str) -> bool {
+    let words: Vec<String> = sql
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|x| !x.is_empty())
+        .take(4)
+        .map(|x| x.to_uppercase())
+        .collect();
+    let words: Vec<&str> = words.iter().map(|x| x.as_str()).collect();
+    let create = match words.as_slice() {
+        ["SELECT", ..] | ["WITH", ..] | ["VALUES", ..] | ["ASSERT", ..] => return true,
+        ["CREATE", "OR", "REPLACE", rest @ ..] => rest,
+        ["CREATE", rest @ ..] => rest,
+        _ => return false,
+    };
+    matches!(create, ["VIEW", ..] | ["TABLE", ..] | ["MATERIALIZED", ..])
+}
 
-            select.projection.iter().for_each(|x| match x {
-                SelectItem::ExprWithAlias { expr, .. } => get_refs_expr(expr, vec),
-                SelectItem::UnnamedExpr(expr) => get_refs_expr(expr, vec),
-                _ => {}
-            });
+/// Parses the statements of a file after rendering its template expressions,
+/// returning every statement with its line and the models referred to with `ref`
+#[allow(clippy::type_complexity)]
+fn parse_file(
+    path: &str,
+    context: &template::Context,
+) -> Result<(Vec<(FileStatement, Option<u64>)>, Vec<String>), PowerSqlError> {
+    let sql = read_file(path)?;
+    let rendered = template::render(path, &sql, context)?;
+    let mut statements = vec![];
+    for range in rendered.statements() {
+        let line = rendered.line(range.start);
+        let sql = &rendered.sql[range.clone()];
+        match Parser::parse_sql(&PowerSqlDialect {}, sql) {
+            Ok(parsed) => statements.extend(
+                parsed
+                    .into_iter()
+                    .map(|x| (FileStatement::Parsed(x), Some(line))),
+            ),
+            Err(_) if !is_model_statement(sql) => {
+                statements.push((FileStatement::Raw(sql.trim_end().to_string()), Some(line)))
+            }
+            Err(err) => {
+                return Err(
+                    match rendered.map_error(range.start, PowerSqlError::parse(path, err)) {
+                        // Errors without position are reported at the start of the statement
+                        PowerSqlError::Parse {
+                            file,
+                            line: None,
+                            message,
+                            ..
+                        } => PowerSqlError::Parse {
+                            file,
+                            line: Some(line),
+                            column: None,
+                            message,
+                        },
+                        err => err,
+                    },
+                );
+            }
         }
-        _ => {}
     }
+    Ok((statements, rendered.refs))
+}
+
+/// Template variables from [vars] in powersql.toml, overridden by --vars name=value
+fn template_vars(
+    config: &HashMap<String, toml::Value>,
+    args: &[String],
+) -> Result<HashMap<String, String>, PowerSqlError> {
+    let mut vars: HashMap<String, String> = config
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                toml::Value::String(x) => x.clone(),
+                x => x.to_string(),
+            };
+            (name.clone(), value)
+        })
+        .collect();
+    for arg in args {
+        let position = arg.find('=').ok_or_else(|| {
+            PowerSqlError::config(format!("Expected name=value in --vars, got {}", arg))
+        })?;
+        vars.insert(arg[..position].to_string(), arg[position + 1..].to_string());
+    }
+    Ok(vars)
 }
 
-fn get_refs(query: &Query, vec: &mut Vec<String>) {
-    query.ctes.iter().for_each(|x| get_refs_cte(x, vec));
-    get_refs_set_expr(&query.body, vec);
+/// Setting configured for the directory of a model file, the most specific directory wins
+fn directory_setting<'a>(
+    path: &str,
+    directories: &'a HashMap<String, DirectoryConfig>,
+    setting: impl Fn(&'a DirectoryConfig) -> Option<&'a str>,
+) -> Option<&'a str> {
+    directories
+        .iter()
+        .filter(|(dir, _)| select::in_directory(path, dir))
+        .filter_map(|(dir, config)| setting(config).map(|value| (dir, value)))
+        .max_by_key(|(dir, _)| select::normal_components(Path::new(dir)).len())
+        .map(|(_, value)| value)
 }
 
-fn get_refs_expr(expr: &Expr, vec: &mut Vec<String>) {
-    match expr {
-        Expr::Between { low, high, .. } => {
-            get_refs_expr(low, vec);
-            get_refs_expr(high, vec);
-        }
-        Expr::BinaryOp { left, right, .. } => {
-            get_refs_expr(left, vec);
-            get_refs_expr(right, vec);
+const MATERIALIZATIONS: &[&str] = &["view", "table", "materialized_view", "incremental"];
+
+/// Materialization of a model configured in [models] or else for its directory
+fn materialization<'a>(
+    name: &str,
+    path: &str,
+    models: &'a HashMap<String, ModelConfig>,
+    directories: &'a HashMap<String, DirectoryConfig>,
+) -> Option<&'a str> {
+    models
+        .get(name)
+        .and_then(|x| x.materialized.as_deref())
+        .or_else(|| directory_setting(path, directories, |x| x.materialized.as_deref()))
+}
+
+/// Statement creating a SELECT model with the given materialization
+fn select_model(
+    name: &ObjectName,
+    query: &Query,
+    materialized: &str,
+) -> Result<Statement, PowerSqlError> {
+    let create = match materialized {
+        "view" => "CREATE VIEW",
+        "materialized_view" => "CREATE MATERIALIZED VIEW",
+        "table" | "incremental" => "CREATE TABLE",
+        x => {
+            return Err(PowerSqlError::config(format!(
+                "Unknown materialization {} for model {}, expected one of: {}",
+                x,
+                name,
+                MATERIALIZATIONS.join(", ")
+            )))
         }
-        Expr::Cast { expr, .. } => {
-            get_refs_expr(expr, vec);
+    };
+    let sql = format!("{} {} AS {}", create, name, query);
+    Parser::parse_sql(&PowerSqlDialect {}, &sql)
+        .map(|mut x| x.remove(0))
+        .map_err(|err| PowerSqlError::config(format!("Error in model {}: {}", name, err)))
+}
+
+/// Points unqualified references to a model in the same schema as the statement
+/// to that model, like the schema search path of the database would
+fn qualify_refs(statement: &mut Statement, models: &HashSet<String>) {
+    let (name, query) = match statement {
+        Statement::CreateView { name, query, .. } => (name, query),
+        Statement::CreateTable {
+            name,
+            query: Some(query),
+            ..
+        } => (name, query),
+        _ => return,
+    };
+    let schema = match name.0.split_last() {
+        Some((_, schema)) if !schema.is_empty() => schema.to_vec(),
+        _ => return,
+    };
+    visit_tables_query(query, &im::HashSet::new(), &mut |reference| {
+        if let [table] = reference.0.as_slice() {
+            let mut qualified = schema.clone();
+            qualified.push(table.clone());
+            let qualified = ObjectName(qualified);
+            // A model reading a model with its own name reads the one in the default schema
+            if qualified != *name && models.contains(&qualified.to_string()) {
+                *reference = qualified;
+            }
         }
-        Expr::Collate { expr, .. } => {
-            get_refs_expr(expr, vec);
+    });
+}
+
+/// Loads the models, returning the statement and the file of every model.
+/// Models are named by their qualified name, models without schema get the
+/// schema of their directory when configured. A `SELECT` model is named after
+/// its file and created with the materialization configured for it, a view by default.
+/// Other statements are hooks of the model in the same file: statements before the
+/// first model run before it, later statements after the model they follow.
+/// The hooks in `[models]` run after the hooks in the file.
+fn load_asts(
+    models: &[String],
+    directories: &HashMap<String, DirectoryConfig>,
+    configs: &HashMap<String, ModelConfig>,
+    context: &template::Context,
+) -> Result<Models, PowerSqlError> {
+    let mut res = HashMap::new();
+    let mut paths = HashMap::new();
+    let mut hooks: HashMap<String, Hooks> = HashMap::new();
+    let mut warnings = vec![];
+    let mut refs = vec![];
+    for path in models.iter() {
+        let (statements, file_refs) = parse_file(path, context)?;
+        let schema = directory_setting(path, directories, |x| x.schema.as_deref());
+        for model in file_refs {
+            refs.push((schema.map(|x| format!("{}.{}", x, model)), model));
         }
-        Expr::Exists(query) => get_refs(query, vec),
-        Expr::Extract { expr, .. } => get_refs_expr(expr, vec),
-        Expr::Function(Function { args, .. }) => {
-            for arg in args {
-                get_refs_expr(arg, vec);
+        let with_schema = |mut name: ObjectName| {
+            if let (Some(schema), 1) = (schema, name.0.len()) {
+                name.0.insert(
+                    0,
+                    Ident {
+                        value: schema.to_string(),
+                        quote_style: None,
+                    },
+                );
             }
+            name
+        };
+
+        let selects = statements
+            .iter()
+            .filter(|x| matches!(x.0, FileStatement::Parsed(Statement::Query(_))))
+            .count();
+        if selects > 1 {
+            return Err(PowerSqlError::config(format!(
+                "{} has {} SELECT statements, a SELECT model should be the only query in its file",
+                path, selects
+            )));
         }
-        Expr::InSubquery { expr, subquery, .. } => {
-            get_refs_expr(expr, vec);
-            get_refs(subquery, vec);
+
+        let mut pending = vec![];
+        let mut last_model: Option<String> = None;
+        for (statement, line) in statements {
+            let mut statement = match statement {
+                FileStatement::Parsed(statement @ Statement::CreateView { .. })
+                | FileStatement::Parsed(
+                    statement @ Statement::CreateTable { query: Some(_), .. },
+                )
+                | FileStatement::Parsed(statement @ Statement::Query(_)) => statement,
+                FileStatement::Parsed(Statement::Assert { .. }) => {
+                    warnings.push(Warning {
+                        file: path.clone(),
+                        line,
+                        message: "ASSERT statements belong in a tests directory, skipped"
+                            .to_string(),
+                    });
+                    continue;
+                }
+                statement => {
+                    match &last_model {
+                        Some(model) => hooks
+                            .entry(model.clone())
+                            .or_default()
+                            .post
+                            .push(statement.to_string()),
+                        None => pending.push((statement.to_string(), line)),
+                    }
+                    continue;
+                }
+            };
+
+            let select = matches!(statement, Statement::Query(_));
+            if let Statement::Query(query) = &statement {
+                let stem = Path::new(path).file_stem().unwrap_or_default();
+                let name = with_schema(ObjectName(vec![Ident {
+                    value: stem.to_string_lossy().to_string(),
+                    quote_style: None,
+                }]));
+                let materialized = materialization(&name.to_string(), path, configs, directories)
+                    .unwrap_or("view");
+                statement = select_model(&name, query, materialized)?;
+            }
+
+            let name = match &mut statement {
+                Statement::CreateView { name, .. }
+                | Statement::CreateTable {
+                    name,
+                    query: Some(_),
+                    ..
+                } => name,
+                _ => unreachable!("Only models are left"),
+            };
+            *name = with_schema(name.clone());
+            let name = name.to_string();
+            match configs.get(&name).and_then(|x| x.materialized.as_deref()) {
+                Some(x) if !select && x != "incremental" => {
+                    return Err(PowerSqlError::config(format!(
+                        "Model {} in {} uses CREATE, materialized = \"{}\" only applies to SELECT models",
+                        name, path, x
+                    )))
+                }
+                _ => {}
+            }
+            if let Some(other) = paths.get(&name) {
+                return Err(PowerSqlError::config(format!(
+                    "Model {} is defined in both {} and {}",
+                    name, other, path
+                )));
+            }
+            if !pending.is_empty() {
+                let pre = pending.drain(..).map(|x| x.0).collect();
+                hooks.entry(name.clone()).or_default().pre = pre;
+            }
+            last_model = Some(name.clone());
+            paths.insert(name.clone(), path.clone());
+            res.insert(name, statement);
         }
-        Expr::IsNotNull(expr) => {
-            get_refs_expr(expr, vec);
+        for (_statement, line) in pending {
+            warnings.push(Warning {
+                file: path.clone(),
+                line,
+                message: "Not a model and there is no model in the file to run it with, skipped"
+                    .to_string(),
+            });
         }
-        Expr::IsNull(expr) => {
-            get_refs_expr(expr, vec);
+    }
+
+    // A ref() in a directory with a schema can refer to a model in that schema
+    for (qualified, model) in refs {
+        if !res.contains_key(&model) && !qualified.map_or(false, |x| res.contains_key(&x)) {
+            return Err(PowerSqlError::MissingModel { model });
         }
-        Expr::ListAgg(ListAgg { expr, .. }) => {
-            get_refs_expr(expr, vec);
+    }
+
+    let names = res.keys().cloned().collect();
+    for statement in res.values_mut() {
+        qualify_refs(statement, &names);
+    }
+
+    for (name, config) in configs.iter() {
+        if config.pre_hook.is_none() && config.post_hook.is_none() {
+            continue;
         }
-        Expr::Nested(expr) => {
-            get_refs_expr(expr, vec);
+        if !res.contains_key(name) {
+            return Err(PowerSqlError::config(format!(
+                "Hooks configured for model {}, which doesn't exist",
+                name
+            )));
         }
-        Expr::Subquery(query) => get_refs(query, vec),
-        Expr::UnaryOp { expr, .. } => get_refs_expr(expr, vec),
-        _ => {}
+        let model_hooks = hooks.entry(name.clone()).or_default();
+        model_hooks
+            .pre
+            .extend(config.pre_hook.iter().flatten().cloned());
+        model_hooks
+            .post
+            .extend(config.post_hook.iter().flatten().cloned());
     }
+    Ok(Models {
+        asts: res,
+        paths,
+        hooks,
+        warnings,
+    })
 }
 
-fn load_asts(models: &[String]) -> Result<HashMap<String, Statement>, String> {
+/// Loads the macro definitions, by name
+fn load_macros(files: &[String]) -> Result<HashMap<String, macros::Macro>, PowerSqlError> {
     let mut res = HashMap::new();
-    for path in models.iter() {
-        let sql = fs::read_to_string(path).map_err(|_x| format!("Error while reading {}", path))?;
+    for path in files.iter() {
+        let sql = read_file(path)?;
+        for (name, m) in macros::parse_macros(path, &sql)? {
+            if res.insert(name.clone(), m).is_some() {
+                return Err(PowerSqlError::config(format!(
+                    "Macro {} is defined more than once, last in {}",
+                    name, path
+                )));
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Loads the column types of external tables from `CREATE TABLE` statements
+fn load_sources(files: &[String]) -> Result<im::HashMap<String, types::TableType>, PowerSqlError> {
+    let mut res = im::HashMap::new();
+    for path in files.iter() {
+        let sql = read_file(path)?;
         let statements = Parser::parse_sql(&PowerSqlDialect {}, &sql)
-            .map_err(|err| format!("Parse Error in {}: {}", path, err))?;
+            .map_err(|err| PowerSqlError::parse(path, err))?;
 
         for statement in statements {
-            let name = match &statement {
-                Statement::CreateView { name, .. } => format!("{}", name),
+            match statement {
                 Statement::CreateTable {
                     name,
-                    query: Some(_),
+                    columns,
+                    query: None,
                     ..
-                } => format!("{}", name),
-                _ => unimplemented!("Only (materialized) view and create table as supported "),
-            };
-            res.insert(name, statement);
+                } => {
+                    let columns = columns
+                        .iter()
+                        .map(|c| (c.name.value.clone(), types::column_type(c)))
+                        .collect();
+                    res.insert(unquoted_name(&name), types::TableType::Closed(columns));
+                }
+                _ => {
+                    return Err(PowerSqlError::config(format!(
+                        "Only CREATE TABLE statements are supported in source file {}",
+                        path
+                    )))
+                }
+            }
         }
     }
     Ok(res)
 }
 
-fn load_tests(models: &[String]) -> Result<Vec<Test>, String> {
+/// Loads the `ASSERT` statements of the test files, other statements are skipped with a warning
+fn load_tests(
+    models: &[String],
+    context: &template::Context,
+) -> Result<(Vec<Test>, Vec<Warning>), PowerSqlError> {
     let mut res = vec![];
+    let mut warnings = vec![];
     for path in models.iter() {
-        let sql = fs::read_to_string(path).map_err(|_x| format!("Error while reading {}", path))?;
-
-        let statements = Parser::parse_sql(&PowerSqlDialect {}, &sql)
-            .map_err(|err| format!("Parse Error in {}: {}", path, err))?;
+        let (statements, _refs) = parse_file(path, context)?;
 
-        for statement in statements {
-            let query = match statement {
-                Statement::Assert {
+        for (statement, line) in statements {
+            let message = match statement {
+                FileStatement::Parsed(Statement::Assert {
                     message: Some(Expr::Value(Value::SingleQuotedString(msg))),
                     condition,
-                } => Test {
+                }) => {
+                    res.push(Test {
+                        condition,
+                        message: msg,
+                    });
+                    continue;
+                }
+                // Without a message the test is described by its condition
+                FileStatement::Parsed(Statement::Assert {
+                    message: None,
                     condition,
-                    message: msg,
-                },
-                _ => unimplemented!("Only assert statements are supported in test files"),
+                }) => {
+                    res.push(Test {
+                        message: condition.to_string(),
+                        condition,
+                    });
+                    continue;
+                }
+                FileStatement::Parsed(Statement::Assert { .. }) => {
+                    "The message of ASSERT should be a string, skipped"
+                }
+                _ => "Only ASSERT statements are supported in test files, skipped",
             };
-            res.push(query)
+            warnings.push(Warning {
+                file: path.clone(),
+                line,
+                message: message.to_string(),
+            });
         }
     }
-    Ok(res)
+    Ok((res, warnings))
 }
 
-fn get_query(statement: &Statement) -> &Query {
-    match statement {
-        Statement::CreateView { query, .. } => query,
-        Statement::CreateTable {
-            query: Some(query), ..
-        } => query,
-        Statement::Query(query) => query,
-        _ => unreachable!("Expected view, table, of query in fn get_query"),
+/// Keeps the tests that refer to a selected model, or all tests without selection
+fn select_tests(tests: Vec<Test>, selection: &Selection, selected: &HashSet<String>) -> Vec<Test> {
+    if selection.select.is_empty() && selection.exclude.is_empty() {
+        return tests;
     }
+    tests
+        .into_iter()
+        .filter(|test| {
+            let mut refs = vec![];
+            get_refs_expr(&test.condition, &mut refs);
+            refs.iter().any(|x| selected.contains(x))
+        })
+        .collect()
+}
+
+fn parse_expr(sql: &str) -> Result<Expr, ParserError> {
+    let tokens = Tokenizer::new(&PowerSqlDialect {}, sql)
+        .tokenize()
+        .map_err(ParserError::from)?;
+    Parser::new(tokens).parse_expr()
+}
+
+/// Incremental models, configured in [models] or for their directory.
+/// For a directory, `incremental` only applies to the tables in it.
+fn get_incremental(
+    models: &HashMap<String, ModelConfig>,
+    directories: &HashMap<String, DirectoryConfig>,
+    asts: &HashMap<String, Statement>,
+    paths: &HashMap<String, String>,
+) -> Result<HashMap<String, Incremental>, PowerSqlError> {
+    let mut res = HashMap::new();
+    let names: HashSet<&String> = models.keys().chain(asts.keys()).collect();
+    for name in names {
+        let model = models.get(name);
+        let path = paths.get(name).map_or("", |x| x.as_str());
+        match materialization(name, path, models, directories) {
+            Some("incremental") => {}
+            Some(x) if MATERIALIZATIONS.contains(&x) => continue,
+            Some(x) => {
+                return Err(PowerSqlError::config(format!(
+                    "Unknown materialization {} for model {}, expected one of: {}",
+                    x,
+                    name,
+                    MATERIALIZATIONS.join(", ")
+                )))
+            }
+            None => continue,
+        }
+        let configured = model.and_then(|x| x.materialized.as_ref()).is_some();
+        match asts.get(name) {
+            Some(Statement::CreateTable { query: Some(_), .. }) => {}
+            Some(_) if !configured => continue,
+            Some(_) => {
+                return Err(PowerSqlError::config(format!(
+                    "Incremental model {} should use CREATE TABLE AS",
+                    name
+                )))
+            }
+            None => {
+                return Err(PowerSqlError::MissingModel {
+                    model: name.clone(),
+                })
+            }
+        }
+        let filter = match model.and_then(|x| x.filter.as_ref()) {
+            Some(filter) => Some(parse_expr(filter).map_err(|err| {
+                PowerSqlError::config(format!("Parse Error in filter of {}: {}", name, err))
+            })?),
+            None => None,
+        };
+        res.insert(
+            name.clone(),
+            Incremental {
+                unique_key: model.and_then(|x| x.unique_key.clone()),
+                filter,
+            },
+        );
+    }
+    Ok(res)
 }
 
 fn get_refs_statement(statement: &Statement, vec: &mut Vec<String>) {
@@ -215,42 +726,148 @@ fn get_dependencies(asts: &HashMap<String, Statement>) -> HashMap<String, Vec<St
         .map(|(src, stmt)| {
             let mut x = vec![];
             get_refs_statement(&stmt, &mut x);
-            (
-                src.clone(),
-                x.iter()
-                    .filter(|elem| asts.contains_key(*elem))
-                    .cloned()
-                    .collect(),
-            )
+            let mut deps: Vec<String> = vec![];
+            for elem in x {
+                // A model reading from its previous version doesn't depend on itself
+                if elem != *src && asts.contains_key(&elem) && !deps.contains(&elem) {
+                    deps.push(elem);
+                }
+            }
+            (src.clone(), deps)
         })
         .collect()
 }
 
-fn detect_cycles(deps: &HashMap<String, Vec<String>>) -> Result<(), String> {
-    let mut visited_all = HashSet::new();
-    for (model, model_deps) in deps.iter() {
-        let mut visited = HashSet::new();
-        let mut stack = model_deps.clone();
+// Tarjan's algorithm for strongly connected components
+struct Components<'a> {
+    deps: &'a HashMap<String, Vec<String>>,
+    index: usize,
+    indices: HashMap<&'a str, usize>,
+    low_links: HashMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+    on_stack: HashSet<&'a str>,
+    components: Vec<Vec<&'a str>>,
+}
 
-        visited.insert(model);
-        while let Some(x) = stack.pop() {
-            if visited_all.contains(&x) {
-                continue;
+impl<'a> Components<'a> {
+    fn visit(&mut self, model: &'a str) {
+        self.indices.insert(model, self.index);
+        self.low_links.insert(model, self.index);
+        self.index += 1;
+        self.stack.push(model);
+        self.on_stack.insert(model);
+
+        let deps = self.deps;
+        for next in deps[model].iter() {
+            let next = next.as_str();
+            if !self.indices.contains_key(next) {
+                self.visit(next);
+                let low_link = self.low_links[model].min(self.low_links[next]);
+                self.low_links.insert(model, low_link);
+            } else if self.on_stack.contains(next) {
+                let low_link = self.low_links[model].min(self.indices[next]);
+                self.low_links.insert(model, low_link);
             }
-            visited_all.insert(x.clone());
+        }
+
+        if self.low_links[model] == self.indices[model] {
+            let mut component = vec![];
+            while let Some(x) = self.stack.pop() {
+                self.on_stack.remove(x);
+                component.push(x);
+                if x == model {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
 
-            let d = deps.get(&x).ok_or(format!("Model {} not found", &x))?;
+/// Shortest path from `start` back to itself, staying within the component
+fn cycle_in_component(
+    deps: &HashMap<String, Vec<String>>,
+    component: &HashSet<&str>,
+    start: &str,
+) -> Vec<String> {
+    let mut parents: HashMap<&str, &str> = HashMap::new();
+    let mut queue = std::collections::VecDeque::new();
+    queue.push_back(start);
 
-            for i in d.iter() {
-                if visited.contains(i) {
-                    return Err(format!("Loop detected while checking model {}", model));
+    while let Some(x) = queue.pop_front() {
+        for next in deps[x].iter().map(|n| n.as_str()) {
+            if !component.contains(next) || parents.contains_key(next) {
+                continue;
+            }
+            parents.insert(next, x);
+            if next == start {
+                let mut path = vec![start.to_string()];
+                let mut current = parents[start];
+                while current != start {
+                    path.push(current.to_string());
+                    current = parents[current];
                 }
-                visited.insert(i);
+                path.push(start.to_string());
+                path.reverse();
+                return path;
             }
-            stack.extend(d.clone());
+            queue.push_back(next);
         }
     }
-    Ok(())
+    unreachable!("Strongly connected component without cycle")
+}
+
+/// Reports every independent cycle, with the files of the models involved
+fn detect_cycles(
+    deps: &HashMap<String, Vec<String>>,
+    paths: &HashMap<String, String>,
+) -> Result<(), PowerSqlError> {
+    for model_deps in deps.values() {
+        for d in model_deps {
+            if !deps.contains_key(d) {
+                return Err(PowerSqlError::MissingModel { model: d.clone() });
+            }
+        }
+    }
+
+    let mut components = Components {
+        deps,
+        index: 0,
+        indices: HashMap::new(),
+        low_links: HashMap::new(),
+        stack: vec![],
+        on_stack: HashSet::new(),
+        components: vec![],
+    };
+    let mut models: Vec<&str> = deps.keys().map(|x| x.as_str()).collect();
+    models.sort();
+    for model in models {
+        if !components.indices.contains_key(model) {
+            components.visit(model);
+        }
+    }
+
+    let mut cycles: Vec<Vec<String>> = components
+        .components
+        .iter()
+        .filter(|c| c.len() > 1 || deps[c[0]].iter().any(|x| x == c[0]))
+        .map(|c| {
+            let start = c.iter().min().unwrap();
+            cycle_in_component(deps, &c.iter().copied().collect(), start)
+        })
+        .collect();
+
+    if cycles.is_empty() {
+        return Ok(());
+    }
+    cycles.sort();
+
+    let files = cycles
+        .iter()
+        .flatten()
+        .filter_map(|m| paths.get(m).map(|p| (m.clone(), p.clone())))
+        .collect();
+    Err(PowerSqlError::Cycle { cycles, files })
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -259,7 +876,9 @@ struct ModelNode {
     next_nodes: Vec<String>,
 }
 
-fn build_graph(deps: &HashMap<String, Vec<String>>) -> Result<HashMap<&str, ModelNode>, String> {
+fn build_graph(
+    deps: &HashMap<String, Vec<String>>,
+) -> Result<HashMap<&str, ModelNode>, PowerSqlError> {
     let mut graph = HashMap::new();
 
     let mut nodes = Vec::new();
@@ -286,66 +905,258 @@ fn build_graph(deps: &HashMap<String, Vec<String>>) -> Result<HashMap<&str, Mode
     Ok(graph)
 }
 
-fn find_test_files(tests: Option<Vec<String>>) -> Vec<String> {
-    let mut tests_models = vec![];
-    if let Some(tests) = tests {
-        for dir in tests {
-            for entry in WalkDir::new(dir.to_string()) {
-                let entry = entry.unwrap();
-                if let Some(ext) = entry.path().extension() {
-                    {
-                        if ext == "sql" {
-                            tests_models.push(entry.path().to_str().unwrap().to_string());
-                        }
+fn find_sql_files(dirs: &[String]) -> Vec<String> {
+    let mut files = vec![];
+    for dir in dirs {
+        for entry in WalkDir::new(dir.to_string()) {
+            let entry = entry.unwrap();
+            if let Some(ext) = entry.path().extension() {
+                if ext == "sql" {
+                    files.push(entry.path().to_str().unwrap().to_string());
+                }
+            }
+        }
+    }
+    files
+}
+
+/// Runs the hooks in order, stopping at the first failing hook
+async fn run_hooks(
+    executor: &mut dyn Executor,
+    hooks: &[String],
+    kind: &str,
+) -> Result<(), PowerSqlError> {
+    for (i, sql) in hooks.iter().enumerate() {
+        executor
+            .execute_raw(sql)
+            .await
+            .map_err(|error| PowerSqlError::Hook {
+                model: None,
+                hook: format!("{} {}", kind, i + 1),
+                sql: sql.clone(),
+                error,
+            })?;
+    }
+    Ok(())
+}
+
+/// Builds a model, running its hooks before and after it
+async fn build_model(
+    executor: &mut dyn Executor,
+    stmt: &mut Statement,
+    incremental: Option<&Incremental>,
+    hooks: &Hooks,
+) -> Result<(), PowerSqlError> {
+    run_hooks(executor, &hooks.pre, "pre_hook").await?;
+    match incremental {
+        Some(incremental) => executor.execute_incremental(stmt, incremental).await?,
+        None => executor.execute(stmt).await?,
+    }
+    run_hooks(executor, &hooks.post, "post_hook").await
+}
+
+/// Executes the models in dependency order, running up to one model per executor concurrently.
+/// After a failure no new models are started, the running ones are awaited before returning.
+async fn run_graph(
+    mut graph: HashMap<&str, ModelNode>,
+    asts: &HashMap<String, Statement>,
+    hooks: &HashMap<String, Hooks>,
+    incremental: &HashMap<String, Incremental>,
+    executors: &mut Vec<Box<dyn Executor>>,
+) -> Result<(), PowerSqlError> {
+    let mut nodes: Vec<_> = graph
+        .iter()
+        .filter(|(_m, node)| node.live_parents == 0)
+        .map(|(x, _)| (*x).to_string())
+        .collect();
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut running = 0;
+    let mut error = None;
+
+    loop {
+        while error.is_none() && !executors.is_empty() {
+            let m = match nodes.pop() {
+                Some(m) => m,
+                None => break,
+            };
+            let mut executor = executors.pop().unwrap();
+            let mut stmt = asts.get(&m).unwrap().clone();
+            let incremental = incremental.get(&m).cloned();
+            let hooks = hooks.get(&m).cloned().unwrap_or_default();
+            let sender = sender.clone();
+
+            println!("Executing {}", m);
+            tokio::spawn(async move {
+                let res =
+                    build_model(&mut *executor, &mut stmt, incremental.as_ref(), &hooks).await;
+                let _ = sender.send((m, executor, res));
+            });
+            running += 1;
+        }
+
+        if running == 0 {
+            break;
+        }
+
+        let (m, executor, res) = receiver
+            .recv()
+            .await
+            .ok_or_else(|| BackendError::Connection {
+                message: "Executor task stopped unexpectedly".to_string(),
+            })?;
+        running -= 1;
+        executors.push(executor);
+
+        match res {
+            Ok(()) => {
+                println!("Ready {}", m);
+
+                let node = graph.get(m.as_str()).unwrap().clone();
+                for n in node.next_nodes.iter() {
+                    let mut node = graph.get_mut(n.as_str()).unwrap();
+                    node.live_parents -= 1;
+                    if node.live_parents == 0 {
+                        nodes.push(n.to_string());
                     }
                 }
             }
+            Err(err) => {
+                println!("Failed {}", m);
+                error = error.or_else(|| Some(err.in_model(&m)));
+            }
         }
     }
-    tests_models
+
+    match error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+#[allow(unused_mut)]
+fn available_backends() -> Vec<&'static str> {
+    let mut backends = vec![];
+    #[cfg(feature = "postgres")]
+    backends.push("postgres");
+    #[cfg(feature = "bigquery")]
+    backends.push("bigquery");
+    #[cfg(feature = "sqlite")]
+    backends.push("sqlite");
+    backends
 }
 
-#[cfg(feature = "bigquery")]
-async fn get_executor() -> Result<execute::BigqueryRunner, String> {
-    execute::BigqueryRunner::new().await
+fn resolve_backend(config: Option<&Target>) -> Result<String, PowerSqlError> {
+    if let Some(backend) = config.and_then(|x| x.backend.as_deref()) {
+        return Ok(backend.to_string());
+    }
+    // Without explicit target, a binary built with a single backend uses that one
+    match available_backends().as_slice() {
+        [backend] => Ok(backend.to_string()),
+        [] => Err(PowerSqlError::config(
+            "PowerSQL was built without backends, enable one of the features postgres, bigquery or sqlite",
+        )),
+        backends => Err(PowerSqlError::config(format!(
+            "No target configured, use --target or a [target] section with one of: {}",
+            backends.join(", ")
+        ))),
+    }
 }
 
-#[cfg(feature = "postgres")]
-async fn get_executor() -> Result<execute::Postgres, String> {
-    execute::Postgres::new().await
+/// Profile of the target selected with --target or in [target]. A target that is not
+/// a profile is a backend, connecting with the settings from the environment.
+fn resolve_target(
+    target: Option<&str>,
+    config: Option<&Target>,
+    profiles: &HashMap<String, Profile>,
+) -> Result<Profile, PowerSqlError> {
+    let name = target.or_else(|| config.and_then(|x| x.profile.as_deref()));
+    let mut profile = match name.map(|name| (name, profiles.get(name))) {
+        Some((name, Some(profile))) => profile.interpolated(name)?,
+        Some((name, None)) if target.is_some() => Profile::for_backend(name),
+        Some((name, None)) => {
+            return Err(PowerSqlError::config(format!(
+                "Profile {} of [target] not found in [profiles]",
+                name
+            )))
+        }
+        None => Profile::default(),
+    };
+    if profile.backend.is_none() {
+        profile.backend = Some(resolve_backend(config)?);
+    }
+    Ok(profile)
+}
+
+async fn get_executor(profile: &Profile) -> Result<Box<dyn Executor>, PowerSqlError> {
+    let backend = profile.backend.as_deref().unwrap_or_default();
+    match backend {
+        #[cfg(feature = "postgres")]
+        "postgres" => Ok(Box::new(execute::Postgres::new(profile).await?)),
+        #[cfg(feature = "bigquery")]
+        "bigquery" => Ok(Box::new(execute::BigqueryRunner::new(profile).await?)),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Box::new(execute::Sqlite::new(profile).await?)),
+        _ => Err(PowerSqlError::config(format!(
+            "Unknown target {}, available targets: {}",
+            backend,
+            available_backends().join(", ")
+        ))),
+    }
 }
 
 #[tokio::main]
-pub async fn main() -> Result<(), String> {
+pub async fn main() {
     let opt = Opt::from_args();
 
+    if let Err(err) = run_command(opt).await {
+        eprintln!("{}", err);
+        std::process::exit(err.exit_code());
+    }
+}
+
+async fn run_command(opt: Opt) -> Result<(), PowerSqlError> {
     // Load project
-    let contents = fs::read_to_string("powersql.toml").expect("No powersql.toml file found");
-    let config = toml::from_str(&contents);
-    let config: PowerSqlConfig = match config {
-        Err(x) => return Err(x.to_string()),
-        Ok(conf) => conf,
+    let contents = fs::read_to_string("powersql.toml")
+        .map_err(|_x| PowerSqlError::config("No powersql.toml file found"))?;
+    let config: PowerSqlConfig =
+        toml::from_str(&contents).map_err(|x| PowerSqlError::config(x.to_string()))?;
+    let context = template::Context {
+        vars: template_vars(&config.vars, &opt.vars)?,
+        macros: load_macros(&find_sql_files(
+            config.project.macros.as_deref().unwrap_or(&[]),
+        ))?,
     };
-    let mut models = vec![];
+    let models = find_sql_files(&config.project.models);
+    let Models {
+        asts,
+        paths,
+        hooks,
+        warnings,
+    } = load_asts(&models, &config.directories, &config.models, &context)?;
+    for warning in warnings.iter() {
+        eprintln!("{}", warning);
+    }
+    let all_dependencies: HashMap<String, Vec<String>> = get_dependencies(&asts);
+    detect_cycles(&all_dependencies, &paths)?;
 
-    for dir in config.project.models {
-        for entry in WalkDir::new(dir.to_string()) {
-            let entry = entry.unwrap();
-            if let Some(ext) = entry.path().extension() {
-                {
-                    if ext == "sql" {
-                        models.push(entry.path().to_str().unwrap().to_string());
-                    }
-                }
-            }
+    let selected = match &opt.command {
+        Command::Check { selection }
+        | Command::Run { selection, .. }
+        | Command::Test { selection, .. } => select::select_models(
+            &selection.select,
+            &selection.exclude,
+            &all_dependencies,
+            &paths,
+        )?,
+        Command::Docs | Command::Lineage { .. } | Command::Introspect { .. } => {
+            all_dependencies.keys().cloned().collect()
         }
-    }
-    let mut asts = load_asts(&models)?;
-    let dependencies: HashMap<String, Vec<String>> = get_dependencies(&asts);
-    detect_cycles(&dependencies)?;
+    };
+    let dependencies = select::filter_dependencies(&all_dependencies, &selected);
 
     match opt.command {
-        Command::Check => {
+        Command::Check { selection } => {
             let mut graph = build_graph(&dependencies)?;
 
             let mut nodes: Vec<_> = graph
@@ -354,13 +1165,32 @@ pub async fn main() -> Result<(), String> {
                 .map(|(x, _)| (*x).to_string())
                 .collect();
 
-            let mut ty_env = im::HashMap::new();
+            let sources = match &config.project.sources {
+                Some(dirs) => Some(load_sources(&find_sql_files(dirs))?),
+                None => None,
+            };
+            let mut ty_env = sources.clone().unwrap_or_default();
 
             while let Some(m) = nodes.pop() {
                 println!("Checking {}", m);
 
                 let node = graph.get(m.as_str()).unwrap().clone();
-                let ty = types::get_model_type(get_query(asts.get(&m).unwrap()), ty_env.clone())?;
+                let query = get_query(asts.get(&m).unwrap());
+
+                // With declared sources, every table should be a model or a source
+                if let Some(sources) = &sources {
+                    let known = asts.keys().chain(sources.keys()).cloned().collect();
+                    if let Some(table) = types::unknown_tables(query, &known).into_iter().next() {
+                        return Err(PowerSqlError::type_error(
+                            None,
+                            format!("Table {} is neither a model nor a declared source", table),
+                        )
+                        .in_model(&m));
+                    }
+                }
+
+                let ty =
+                    types::get_model_type(query, ty_env.clone()).map_err(|err| err.in_model(&m))?;
                 println!("{} {:?}", m, ty);
                 ty_env = ty_env.update(m.to_string(), ty);
                 println!("ty_env {:?}", ty_env);
@@ -372,46 +1202,65 @@ pub async fn main() -> Result<(), String> {
                     }
                 }
             }
-            let test_models = find_test_files(config.project.tests);
-            let tests = load_tests(&test_models)?;
+            let test_models = find_sql_files(config.project.tests.as_deref().unwrap_or(&[]));
+            let (tests, warnings) = load_tests(&test_models, &context)?;
+            for warning in warnings.iter() {
+                eprintln!("{}", warning);
+            }
+            let tests = select_tests(tests, &selection, &selected);
 
             for Test { condition, .. } in tests {
-                let ty = types::expr_type(&condition, &HashMap::new(), ty_env.clone(), true)?;
+                let ty = types::expr_type(
+                    &condition,
+                    &types::LocalTypeEnv::default(),
+                    ty_env.clone(),
+                    true,
+                )?;
 
-                match ty {
+                match ty.non_null() {
                     types::BaseType::Any | types::BaseType::Boolean => {}
-                    _ => return Err(format!("Expected boolean in test, got {:?}", ty)),
+                    _ => {
+                        return Err(PowerSqlError::type_error(
+                            None,
+                            format!("Expected boolean in test, got {:?}", ty),
+                        ))
+                    }
                 }
             }
         }
-        Command::Run => {
-            let mut graph = build_graph(&dependencies)?;
-
-            let mut nodes: Vec<_> = graph
-                .iter()
-                .filter(|(_m, node)| node.live_parents == 0)
-                .map(|(x, _)| (*x).to_string())
-                .collect();
-
-            let mut executor = get_executor()
-                .await
-                .map_err(|x| format!("Connection error: {}", x))?;
-
-            while let Some(m) = nodes.pop() {
-                println!("Executing {}", m);
-                executor.execute(&m, asts.get_mut(&m).unwrap()).await?;
-                println!("Ready {}", m);
-                println!("Graph {:?}", graph);
+        Command::Run {
+            threads,
+            full_refresh,
+            ..
+        } => {
+            let graph = build_graph(&dependencies)?;
+            let incremental = if full_refresh {
+                HashMap::new()
+            } else {
+                get_incremental(&config.models, &config.directories, &asts, &paths)?
+            };
+            let threads = threads.or(config.project.threads).unwrap_or(1).max(1);
 
-                let node = graph.get(m.as_str()).unwrap().clone();
-                for n in node.next_nodes.iter() {
-                    let mut node = graph.get_mut(n.as_str()).unwrap();
-                    node.live_parents -= 1;
-                    if node.live_parents == 0 {
-                        nodes.push(n.to_string());
-                    }
-                }
+            let profile = resolve_target(
+                opt.target.as_deref(),
+                config.target.as_ref(),
+                &config.profiles,
+            )?;
+            let mut executors = Vec::with_capacity(threads);
+            for _ in 0..threads {
+                executors.push(get_executor(&profile).await?);
             }
+
+            let on_run_start = config.project.on_run_start.as_deref().unwrap_or(&[]);
+            run_hooks(&mut *executors[0], on_run_start, "on_run_start").await?;
+            let res = run_graph(graph, &asts, &hooks, &incremental, &mut executors).await;
+            // The end hooks also run after a failed model, e.g. to log the run
+            let on_run_end = config.project.on_run_end.as_deref().unwrap_or(&[]);
+            let end = match executors.first_mut() {
+                Some(executor) => run_hooks(&mut **executor, on_run_end, "on_run_end").await,
+                None => Ok(()),
+            };
+            res.and(end)?;
         }
         Command::Docs => {
             let arrows: Vec<String> = dependencies
@@ -421,17 +1270,99 @@ pub async fn main() -> Result<(), String> {
             let graph = build_graph(&dependencies)?;
 
             std::fs::create_dir("docs");
-            let mut f =
-                File::create("docs/docs.md").map_err(|_x| "Could not create docs/docs.md")?;
+            let mut f = File::create("docs/docs.md").map_err(|err| PowerSqlError::Io {
+                path: "docs/docs.md".to_string(),
+                message: format!("{}", err),
+            })?;
             for (x, deps) in graph {
                 f.write_fmt(format_args!("#{}\n", x));
             }
         }
-        Command::Test { fail_fast } => {
+        Command::Lineage {
+            column,
+            downstream,
+            json,
+        } => {
+            let sources = match &config.project.sources {
+                Some(dirs) => load_sources(&find_sql_files(dirs))?,
+                None => im::HashMap::new(),
+            };
+            let tables: HashMap<String, Vec<String>> = sources
+                .iter()
+                .map(|(name, ty)| (name.clone(), ty.columns().keys().cloned().collect()))
+                .collect();
+            let graph = lineage::lineage_graph(&asts, &all_dependencies, tables.clone());
+
+            if json {
+                let json = serde_json::to_string_pretty(&graph).map_err(|err| {
+                    PowerSqlError::config(format!("Could not write the lineage as JSON: {}", err))
+                })?;
+                println!("{}", json);
+                return Ok(());
+            }
+            let column = column
+                .as_deref()
+                .and_then(lineage::parse_column)
+                .ok_or_else(|| PowerSqlError::config("Expected a column as model.column"))?;
+            lineage::check_column(&graph, &tables, &column)?;
+            if downstream {
+                for c in lineage::downstream(&graph, &column) {
+                    println!("{}", c);
+                }
+            } else {
+                print!("{}", lineage::upstream_tree(&graph, &column));
+            }
+        }
+        Command::Introspect { output } => {
+            let output = match (output, config.project.sources.as_deref()) {
+                (Some(output), _) => output,
+                (None, Some([dir, ..])) => format!("{}/introspected.sql", dir),
+                (None, _) => {
+                    return Err(PowerSqlError::config(
+                        "No sources directory configured, use --output",
+                    ))
+                }
+            };
+            let profile = resolve_target(
+                opt.target.as_deref(),
+                config.target.as_ref(),
+                &config.profiles,
+            )?;
+            let mut executor = get_executor(&profile).await?;
+
+            // Tables built by models are not sources
+            let tables: Vec<_> = executor
+                .get_schemas()
+                .await?
+                .into_iter()
+                .filter(|table| !asts.contains_key(&table.name))
+                .collect();
+
+            fs::write(&output, introspect::sources_ddl(&tables)).map_err(|err| {
+                PowerSqlError::Io {
+                    path: output.clone(),
+                    message: format!("{}", err),
+                }
+            })?;
+            println!("Wrote {} tables to {}", tables.len(), output);
+        }
+        Command::Test {
+            fail_fast,
+            selection,
+        } => {
             let mut exit_code = 0;
-            let test_models = find_test_files(config.project.tests);
-            let tests = load_tests(&test_models)?;
-            let mut executor = get_executor().await?;
+            let test_models = find_sql_files(config.project.tests.as_deref().unwrap_or(&[]));
+            let (tests, warnings) = load_tests(&test_models, &context)?;
+            for warning in warnings.iter() {
+                eprintln!("{}", warning);
+            }
+            let tests = select_tests(tests, &selection, &selected);
+            let profile = resolve_target(
+                opt.target.as_deref(),
+                config.target.as_ref(),
+                &config.profiles,
+            )?;
+            let mut executor = get_executor(&profile).await?;
 
             for Test { condition, message } in tests.iter() {
                 print!("{}", message);
@@ -484,15 +1415,97 @@ fn test_dependencies_join() {
         x,
         hashmap! {"x".to_string() => vec!["t".to_string()], "t".to_string() => vec![]}
     );
+    assert!(detect_cycles(&x, &HashMap::new()).is_ok());
+}
+
+#[test]
+fn test_refs_cte_shadowing() {
+    let cases = vec![
+        (
+            "WITH orders AS (SELECT a FROM raw) SELECT a FROM orders",
+            vec!["raw"],
+        ),
+        // A CTE is not visible in its own definition
+        ("WITH t AS (SELECT a FROM t) SELECT a FROM t", vec!["t"]),
+        // CTEs are only visible in the query defining them
+        (
+            "SELECT a FROM (WITH t AS (SELECT 1) SELECT a FROM t) AS x JOIN t ON 1 = 1",
+            vec!["t"],
+        ),
+        ("SELECT a FROM (SELECT 1 AS a) AS t", vec![]),
+    ];
+    for (sql, expected) in cases {
+        let query = match &Parser::parse_sql(&PowerSqlDialect {}, sql).unwrap()[0] {
+            Statement::Query(query) => query.clone(),
+            _ => unreachable!(),
+        };
+        let mut refs = vec![];
+        get_refs(&query, &mut refs);
+
+        assert_eq!(refs, expected, "{}", sql);
+    }
+
+    let sql = "create view a as with b as (select 1) select * from b; \
+               create view b as select * from a";
+    let ast = Parser::parse_sql(&PowerSqlDialect {}, sql).unwrap();
+    let deps = get_dependencies(&hashmap! {
+        "a".to_string() => ast[0].clone(),
+        "b".to_string() => ast[1].clone(),
+    });
+
+    assert_eq!(
+        deps,
+        hashmap! {"a".to_string() => vec![], "b".to_string() => vec!["a".to_string()]}
+    );
+    assert!(detect_cycles(&deps, &HashMap::new()).is_ok());
+}
+
+#[test]
+fn test_refs_every_clause() {
+    let cases = vec![
+        ("SELECT a FROM t JOIN u ON u.id IN (SELECT id FROM v)", vec!["t", "u", "v"]),
+        ("SELECT a FROM t LEFT JOIN (u JOIN v ON 1 = 1) ON 1 = 1", vec!["t", "u", "v"]),
+        ("SELECT a FROM t WHERE a IN (SELECT a FROM u)", vec!["t", "u"]),
+        ("SELECT a FROM t WHERE EXISTS (SELECT 1 FROM u)", vec!["t", "u"]),
+        ("SELECT a FROM t WHERE a BETWEEN (SELECT 1 FROM u) AND 2", vec!["t", "u"]),
+        ("SELECT a FROM t GROUP BY a HAVING COUNT(*) > (SELECT 1 FROM u)", vec!["t", "u"]),
+        ("SELECT a FROM t GROUP BY (SELECT 1 FROM u)", vec!["t", "u"]),
+        (
+            "SELECT CASE WHEN a IN (SELECT a FROM u) THEN 1 ELSE (SELECT 2 FROM v) END FROM t",
+            vec!["t", "u", "v"],
+        ),
+        ("SELECT a IN ((SELECT 1 FROM u), 2) FROM t", vec!["t", "u"]),
+        (
+            "SELECT ROW_NUMBER() OVER (PARTITION BY (SELECT 1 FROM u) ORDER BY (SELECT 1 FROM v)) FROM t",
+            vec!["t", "u", "v"],
+        ),
+        ("SELECT a FROM t UNION SELECT a FROM u EXCEPT SELECT a FROM v", vec!["t", "u", "v"]),
+        ("SELECT a FROM t ORDER BY (SELECT 1 FROM u)", vec!["t", "u"]),
+        ("VALUES ((SELECT 1 FROM u))", vec!["u"]),
+        ("WITH c AS (SELECT a FROM u) SELECT a FROM t", vec!["u", "t"]),
+    ];
+    for (sql, expected) in cases {
+        let query = match &Parser::parse_sql(&PowerSqlDialect {}, sql).unwrap()[0] {
+            Statement::Query(query) => query.clone(),
+            _ => unreachable!(),
+        };
+        let mut refs = vec![];
+        get_refs(&query, &mut refs);
+
+        assert_eq!(refs, expected, "{}", sql);
+    }
 }
 
 #[test]
 fn test_cycle_detection_err() {
     assert!(matches!(
-        detect_cycles(&hashmap! {
-            "a".to_string() => vec!["b".to_string()],
-            "b".to_string() => vec!["a".to_string()]
-        }),
+        detect_cycles(
+            &hashmap! {
+                "a".to_string() => vec!["b".to_string()],
+                "b".to_string() => vec!["a".to_string()]
+            },
+            &HashMap::new()
+        ),
         Err(_)
     ));
 }
@@ -500,10 +1513,13 @@ fn test_cycle_detection_err() {
 #[test]
 fn test_cycle_detection_err_not_found() {
     assert!(matches!(
-        detect_cycles(&hashmap! {
-            "a".to_string() => vec!["b".to_string()],
-            "b".to_string() => vec!["c".to_string()]
-        }),
+        detect_cycles(
+            &hashmap! {
+                "a".to_string() => vec!["b".to_string()],
+                "b".to_string() => vec!["c".to_string()]
+            },
+            &HashMap::new()
+        ),
         Err(_)
     ));
 }
@@ -511,14 +1527,48 @@ fn test_cycle_detection_err_not_found() {
 #[test]
 fn test_cycle_detection_ok() {
     assert!(matches!(
-        detect_cycles(&hashmap! {
-            "a".to_string() => vec!["b".to_string()],
-            "b".to_string() => vec![]
-        }),
+        detect_cycles(
+            &hashmap! {
+                "a".to_string() => vec!["b".to_string()],
+                "b".to_string() => vec![]
+            },
+            &HashMap::new()
+        ),
         Ok(_)
     ));
 }
 
+#[test]
+fn test_cycle_detection_reports_all_cycles() {
+    let deps = hashmap! {
+        "a".to_string() => vec!["b".to_string()],
+        "b".to_string() => vec!["c".to_string()],
+        "c".to_string() => vec!["a".to_string(), "d".to_string()],
+        "d".to_string() => vec![],
+        "e".to_string() => vec!["e".to_string()],
+    };
+    let paths = hashmap! {
+        "a".to_string() => "models/a.sql".to_string(),
+        "b".to_string() => "models/finance/b.sql".to_string(),
+    };
+
+    assert_eq!(
+        detect_cycles(&deps, &paths),
+        Err(PowerSqlError::Cycle {
+            cycles: vec![
+                vec![
+                    "a".to_string(),
+                    "b".to_string(),
+                    "c".to_string(),
+                    "a".to_string()
+                ],
+                vec!["e".to_string(), "e".to_string()],
+            ],
+            files: paths.clone(),
+        })
+    );
+}
+
 #[test]
 fn test_build_graph() {
     let deps = hashmap! {
@@ -546,3 +1596,634 @@ fn test_build_graph() {
         }
     );
 }
+
+#[test]
+fn test_resolve_backend_flag_overrides_config() {
+    let config = Target {
+        backend: Some("bigquery".to_string()),
+        profile: None,
+    };
+    let profiles = HashMap::new();
+
+    assert_eq!(
+        resolve_target(Some("postgres"), Some(&config), &profiles),
+        Ok(Profile::for_backend("postgres"))
+    );
+    assert_eq!(
+        resolve_target(None, Some(&config), &profiles),
+        Ok(Profile::for_backend("bigquery"))
+    );
+}
+
+#[test]
+fn test_resolve_target_profiles() {
+    let config = Target {
+        backend: Some("postgres".to_string()),
+        profile: Some("dev".to_string()),
+    };
+    let profiles = hashmap! {
+        "dev".to_string() => Profile {
+            schema: Some("dev".to_string()),
+            ..Profile::default()
+        },
+        "prod".to_string() => Profile {
+            schema: Some("analytics".to_string()),
+            ..Profile::for_backend("bigquery")
+        },
+    };
+
+    // Profiles without backend use the backend of [target]
+    assert_eq!(
+        resolve_target(None, Some(&config), &profiles),
+        Ok(Profile {
+            schema: Some("dev".to_string()),
+            ..Profile::for_backend("postgres")
+        })
+    );
+    assert_eq!(
+        resolve_target(Some("prod"), Some(&config), &profiles),
+        Ok(profiles["prod"].clone())
+    );
+    assert!(resolve_target(None, Some(&config), &HashMap::new()).is_err());
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct RecordingExecutor {
+    executed: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    // Models being built and the most models built at the same time
+    running: std::sync::Arc<std::sync::Mutex<(usize, usize)>>,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl Executor for RecordingExecutor {
+    async fn new(_profile: &Profile) -> Result<RecordingExecutor, BackendError> {
+        Ok(RecordingExecutor::default())
+    }
+    async fn execute(&mut self, stmt: &mut Statement) -> Result<(), BackendError> {
+        let executed = self.executed.clone();
+        let running = self.running.clone();
+        let name = execute::model_name(stmt).to_string();
+        // Blocks its thread like the SQLite and BigQuery clients
+        execute::blocking(move || {
+            {
+                let mut running = running.lock().unwrap();
+                running.0 += 1;
+                running.1 = running.1.max(running.0);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            running.lock().unwrap().0 -= 1;
+            executed.lock().unwrap().push(name);
+            Ok(())
+        })
+        .await
+    }
+    async fn execute_raw(&mut self, sql: &str) -> Result<(), BackendError> {
+        if sql.starts_with("FAIL") {
+            return Err(BackendError::Query {
+                code: None,
+                message: "syntax error".to_string(),
+            });
+        }
+        self.executed.lock().unwrap().push(sql.to_string());
+        Ok(())
+    }
+    async fn query_bool(&mut self, _query: &str) -> Result<bool, BackendError> {
+        Ok(true)
+    }
+    async fn execute_batch(&mut self, _stmts: &[Statement]) -> Result<(), BackendError> {
+        Ok(())
+    }
+    async fn table_exists(&mut self, _name: &ObjectName) -> Result<bool, BackendError> {
+        Ok(false)
+    }
+    async fn get_schemas(&mut self) -> Result<Vec<execute::TableSchema>, BackendError> {
+        Ok(vec![])
+    }
+}
+
+#[tokio::test]
+async fn test_run_graph_parallel() {
+    let sql = "create view a as select 1 from b; create view b as select 1 from c; \
+               create view c as select 1; create view d as select 1";
+    let ast = Parser::parse_sql(&PowerSqlDialect {}, sql).unwrap();
+    let asts = hashmap! {
+        "a".to_string() => ast[0].clone(),
+        "b".to_string() => ast[1].clone(),
+        "c".to_string() => ast[2].clone(),
+        "d".to_string() => ast[3].clone(),
+    };
+    let deps = get_dependencies(&asts);
+    let graph = build_graph(&deps).unwrap();
+
+    let recorder = RecordingExecutor::default();
+    let mut executors: Vec<Box<dyn Executor>> = (0..2)
+        .map(|_| Box::new(recorder.clone()) as Box<dyn Executor>)
+        .collect();
+
+    run_graph(
+        graph,
+        &asts,
+        &HashMap::new(),
+        &HashMap::new(),
+        &mut executors,
+    )
+    .await
+    .unwrap();
+
+    let executed = recorder.executed.lock().unwrap();
+    let position = |m: &str| executed.iter().position(|x| x == m).unwrap();
+    assert_eq!(executed.len(), 4);
+    assert!(position("c") < position("b"));
+    assert!(position("b") < position("a"));
+    // c and d are built at the same time although building blocks the thread
+    assert_eq!(recorder.running.lock().unwrap().1, 2);
+}
+
+#[test]
+fn test_get_incremental() {
+    let sql = "create table a as select 1 as id; create view b as select 1 as id";
+    let ast = Parser::parse_sql(&PowerSqlDialect {}, sql).unwrap();
+    let asts = hashmap! {"a".to_string() => ast[0].clone(), "b".to_string() => ast[1].clone()};
+    let incremental_config = |name: &str| {
+        hashmap! {name.to_string() => ModelConfig {
+            materialized: Some("incremental".to_string()),
+            unique_key: Some("id".to_string()),
+            filter: Some("id > 0".to_string()),
+            pre_hook: None,
+            post_hook: None,
+        }}
+    };
+
+    let paths = HashMap::new();
+    let directories = HashMap::new();
+    let incremental =
+        get_incremental(&incremental_config("a"), &directories, &asts, &paths).unwrap();
+    assert_eq!(incremental["a"].unique_key, Some("id".to_string()));
+    assert!(incremental["a"].filter.is_some());
+
+    assert!(get_incremental(&incremental_config("b"), &directories, &asts, &paths).is_err());
+
+    // A directory setting only makes the tables in the directory incremental
+    let paths = hashmap! {
+        "a".to_string() => "models/events/a.sql".to_string(),
+        "b".to_string() => "models/events/b.sql".to_string(),
+    };
+    let directories = hashmap! {
+        "models/events".to_string() => DirectoryConfig {
+            schema: None,
+            materialized: Some("incremental".to_string()),
+        },
+    };
+    let incremental = get_incremental(&HashMap::new(), &directories, &asts, &paths).unwrap();
+    assert_eq!(
+        incremental,
+        hashmap! {"a".to_string() => Incremental { unique_key: None, filter: None }}
+    );
+}
+
+#[test]
+fn test_load_sources() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let file = dir.join("sources.sql");
+    fs::write(
+        &file,
+        "CREATE TABLE raw_orders (id INT PRIMARY KEY, note TEXT, paid BOOLEAN NOT NULL);\
+         CREATE TABLE \"Shop\".\"Orders\" (id INT);",
+    )
+    .unwrap();
+
+    let sources = load_sources(&[file.to_str().unwrap().to_string()]).unwrap();
+
+    assert_eq!(
+        sources.get("raw_orders"),
+        Some(&types::TableType::Closed(hashmap! {
+            "id".to_string() => types::BaseType::Number,
+            "note".to_string() => types::BaseType::String.nullable(),
+            "paid".to_string() => types::BaseType::Boolean,
+        }))
+    );
+    // Quoted names are keyed without their quotes
+    assert_eq!(
+        sources.get("Shop.Orders"),
+        Some(&types::TableType::Closed(hashmap! {
+            "id".to_string() => types::BaseType::Number.nullable(),
+        }))
+    );
+}
+
+#[test]
+fn test_load_asts_directory_schema() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    fs::create_dir_all(dir.join("marts")).unwrap();
+    let staging = dir.join("staging.sql");
+    let marts = dir.join("marts").join("revenue.sql");
+    fs::write(&staging, "CREATE VIEW revenue AS SELECT 1 AS amount").unwrap();
+    fs::write(
+        &marts,
+        "CREATE VIEW revenue AS SELECT amount FROM revenue; \
+         CREATE VIEW totals AS SELECT amount FROM analytics.revenue JOIN orders ON 1 = 1; \
+         CREATE VIEW raw.orders AS SELECT 1 AS amount",
+    )
+    .unwrap();
+    let directories = hashmap! {
+        dir.join("marts").to_str().unwrap().to_string() => DirectoryConfig {
+            schema: Some("analytics".to_string()),
+            materialized: None,
+        },
+    };
+
+    let files = vec![
+        staging.to_str().unwrap().to_string(),
+        marts.to_str().unwrap().to_string(),
+    ];
+    let Models { asts, paths, .. } = load_asts(
+        &files,
+        &directories,
+        &HashMap::new(),
+        &template::Context::default(),
+    )
+    .unwrap();
+
+    let mut names: Vec<_> = asts.keys().cloned().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "analytics.revenue",
+            "analytics.totals",
+            "raw.orders",
+            "revenue"
+        ]
+    );
+    assert_eq!(paths["analytics.totals"], files[1]);
+
+    // Unqualified references resolve to models in the same schema first
+    let deps = get_dependencies(&asts);
+    assert_eq!(deps["analytics.revenue"], vec!["revenue"]);
+    assert_eq!(deps["analytics.totals"], vec!["analytics.revenue"]);
+    assert_eq!(
+        asts["analytics.totals"].to_string(),
+        "CREATE VIEW analytics.totals AS SELECT amount FROM analytics.revenue JOIN orders ON 1 = 1"
+    );
+}
+
+#[test]
+fn test_directory_setting() {
+    let directories = hashmap! {
+        "./models".to_string() => DirectoryConfig {
+            schema: None,
+            materialized: Some("table".to_string()),
+        },
+        "models/marts".to_string() => DirectoryConfig {
+            schema: None,
+            materialized: Some("view".to_string()),
+        },
+    };
+    let materialized =
+        |path: &str| directory_setting(path, &directories, |x| x.materialized.as_deref());
+
+    assert_eq!(materialized("models/a.sql"), Some("table"));
+    assert_eq!(materialized("./models/a.sql"), Some("table"));
+    assert_eq!(materialized("./models/marts/b.sql"), Some("view"));
+    assert_eq!(materialized("other/c.sql"), None);
+}
+
+#[test]
+fn test_load_asts_duplicate_names() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    fs::create_dir_all(dir.join("staging")).unwrap();
+    fs::create_dir_all(dir.join("marts")).unwrap();
+    let staging = dir.join("staging").join("orders.sql");
+    let marts = dir.join("marts").join("orders.sql");
+    let view = dir.join("views.sql");
+    fs::write(&staging, "SELECT 1 AS id").unwrap();
+    fs::write(&marts, "SELECT 2 AS id").unwrap();
+    fs::write(&view, "CREATE VIEW orders AS SELECT 3 AS id").unwrap();
+    let load = |files: &[&std::path::PathBuf]| {
+        let files: Vec<_> = files
+            .iter()
+            .map(|x| x.to_str().unwrap().to_string())
+            .collect();
+        load_asts(
+            &files,
+            &HashMap::new(),
+            &HashMap::new(),
+            &template::Context::default(),
+        )
+    };
+
+    // The same file name in two directories
+    let message = match load(&[&staging, &marts]) {
+        Err(PowerSqlError::Config { message }) => message,
+        _ => panic!("Expected a duplicate model error"),
+    };
+    assert!(message.contains(staging.to_str().unwrap()), "{}", message);
+    assert!(message.contains(marts.to_str().unwrap()), "{}", message);
+    // A SELECT model and a CREATE VIEW with the same name
+    assert!(load(&[&staging, &view]).is_err());
+    assert!(load(&[&view]).is_ok());
+}
+
+#[test]
+fn test_template_vars() {
+    let config = hashmap! {
+        "start_date".to_string() => toml::Value::String("2020-01-01".to_string()),
+        "limit".to_string() => toml::Value::Integer(10),
+    };
+
+    let vars = template_vars(&config, &["start_date=2020-07-01".to_string()]).unwrap();
+
+    assert_eq!(
+        vars,
+        hashmap! {
+            "start_date".to_string() => "2020-07-01".to_string(),
+            "limit".to_string() => "10".to_string(),
+        }
+    );
+    assert!(template_vars(&config, &["start_date".to_string()]).is_err());
+}
+
+#[test]
+fn test_load_select_models() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    fs::create_dir_all(dir.join("tables")).unwrap();
+    let files = vec![
+        dir.join("orders.sql"),
+        dir.join("tables").join("revenue.sql"),
+        dir.join("tables").join("events.sql"),
+    ];
+    fs::write(&files[0], "SELECT id, amount FROM raw_orders").unwrap();
+    fs::write(&files[1], "SELECT SUM(amount) AS amount FROM orders").unwrap();
+    fs::write(&files[2], "SELECT id FROM raw_events").unwrap();
+    let files: Vec<_> = files
+        .iter()
+        .map(|x| x.to_str().unwrap().to_string())
+        .collect();
+    let directories = hashmap! {
+        dir.join("tables").to_str().unwrap().to_string() => DirectoryConfig {
+            schema: None,
+            materialized: Some("table".to_string()),
+        },
+    };
+    let configs = hashmap! {
+        "events".to_string() => ModelConfig {
+            materialized: Some("materialized_view".to_string()),
+            unique_key: None,
+            filter: None,
+            pre_hook: None,
+            post_hook: None,
+        },
+    };
+
+    let Models { asts, .. } = load_asts(
+        &files,
+        &directories,
+        &configs,
+        &template::Context::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        asts["orders"].to_string(),
+        "CREATE VIEW orders AS SELECT id, amount FROM raw_orders"
+    );
+    assert_eq!(
+        asts["revenue"].to_string(),
+        "CREATE TABLE revenue AS SELECT SUM(amount) AS amount FROM orders"
+    );
+    assert_eq!(
+        asts["events"].to_string(),
+        "CREATE MATERIALIZED VIEW events AS SELECT id FROM raw_events"
+    );
+    assert_eq!(get_dependencies(&asts)["revenue"], vec!["orders"]);
+
+    let configs = hashmap! {
+        "orders".to_string() => ModelConfig {
+            materialized: Some("ephemeral".to_string()),
+            unique_key: None,
+            filter: None,
+            pre_hook: None,
+            post_hook: None,
+        },
+    };
+    assert!(load_asts(
+        &files,
+        &directories,
+        &configs,
+        &template::Context::default()
+    )
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_load_asts_hooks() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let files = vec![dir.join("orders.sql"), dir.join("cleanup.sql")];
+    fs::write(
+        &files[0],
+        "DELETE FROM audit WHERE model = 'orders';\n\
+         CREATE TABLE orders AS SELECT 1 AS id;\n\
+         INSERT INTO audit VALUES ('orders');\n\
+         ASSERT 1 = 1",
+    )
+    .unwrap();
+    fs::write(&files[1], "-- Not a model\n\nDROP TABLE old_orders").unwrap();
+    let files: Vec<_> = files
+        .iter()
+        .map(|x| x.to_str().unwrap().to_string())
+        .collect();
+
+    let configs = hashmap! {
+        "orders".to_string() => ModelConfig {
+            materialized: None,
+            unique_key: None,
+            filter: None,
+            pre_hook: None,
+            post_hook: Some(vec!["GRANT SELECT ON orders TO reporting".to_string()]),
+        },
+    };
+
+    let Models {
+        asts,
+        hooks,
+        warnings,
+        ..
+    } = load_asts(
+        &files,
+        &HashMap::new(),
+        &configs,
+        &template::Context::default(),
+    )
+    .unwrap();
+
+    assert_eq!(asts.len(), 1);
+    assert_eq!(
+        warnings,
+        vec![
+            Warning {
+                file: files[0].clone(),
+                line: Some(4),
+                message: "ASSERT statements belong in a tests directory, skipped".to_string(),
+            },
+            Warning {
+                file: files[1].clone(),
+                line: Some(3),
+                message: "Not a model and there is no model in the file to run it with, skipped"
+                    .to_string(),
+            },
+        ]
+    );
+
+    let executed = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let mut executors: Vec<Box<dyn Executor>> = vec![Box::new(RecordingExecutor {
+        executed: executed.clone(),
+        ..RecordingExecutor::default()
+    })];
+    let graph = build_graph(&get_dependencies(&asts)).unwrap();
+    run_graph(graph, &asts, &hooks, &HashMap::new(), &mut executors)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        *executed.lock().unwrap(),
+        vec![
+            "DELETE FROM audit WHERE model = 'orders'",
+            "orders",
+            "INSERT INTO audit VALUES ('orders')",
+            "GRANT SELECT ON orders TO reporting",
+        ]
+    );
+
+    // A failing hook is reported with its position and SQL, the executor is kept
+    let mut hooks = hooks;
+    hooks.get_mut("orders").unwrap().post[1] = "FAIL".to_string();
+    let graph = build_graph(&get_dependencies(&asts)).unwrap();
+    assert_eq!(
+        run_graph(graph, &asts, &hooks, &HashMap::new(), &mut executors).await,
+        Err(PowerSqlError::Hook {
+            model: Some("orders".to_string()),
+            hook: "post_hook 2".to_string(),
+            sql: "FAIL".to_string(),
+            error: BackendError::Query {
+                code: None,
+                message: "syntax error".to_string(),
+            },
+        })
+    );
+    assert_eq!(executors.len(), 1);
+
+    let configs = hashmap! {
+        "order".to_string() => ModelConfig {
+            materialized: None,
+            unique_key: None,
+            filter: None,
+            pre_hook: Some(vec!["ANALYZE raw_orders".to_string()]),
+            post_hook: None,
+        },
+    };
+    assert!(load_asts(
+        &files,
+        &HashMap::new(),
+        &configs,
+        &template::Context::default()
+    )
+    .is_err());
+}
+
+#[test]
+fn test_load_asts_unparsed_statements() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let customers = dir.join("customers.sql");
+    let broken = dir.join("broken.sql");
+    fs::write(
+        &customers,
+        "GRANT USAGE ON SCHEMA analytics TO reporting;\n\
+         CREATE VIEW customers AS SELECT 1 AS id;\n\
+         GRANT SELECT ON customers TO reporting;\n\
+         COMMENT ON VIEW customers IS 'One row per customer; no duplicates';\n",
+    )
+    .unwrap();
+    fs::write(
+        &broken,
+        "GRANT SELECT ON broken TO reporting;\nCREATE VIEW broken AS SELECT FROM WHERE",
+    )
+    .unwrap();
+    let load = |file: &std::path::PathBuf| {
+        load_asts(
+            &[file.to_str().unwrap().to_string()],
+            &HashMap::new(),
+            &HashMap::new(),
+            &template::Context::default(),
+        )
+    };
+
+    // Statements the parser doesn't support are hooks as written
+    let Models { asts, hooks, .. } = load(&customers).unwrap();
+    assert_eq!(asts.len(), 1);
+    assert_eq!(
+        hooks["customers"],
+        Hooks {
+            pre: vec!["GRANT USAGE ON SCHEMA analytics TO reporting".to_string()],
+            post: vec![
+                "GRANT SELECT ON customers TO reporting".to_string(),
+                "COMMENT ON VIEW customers IS 'One row per customer; no duplicates'".to_string(),
+            ],
+        }
+    );
+
+    // Models are still parsed, with errors at the line of the model
+    match load(&broken) {
+        Err(PowerSqlError::Parse { file, line, .. }) => {
+            assert_eq!(file, broken.to_str().unwrap());
+            assert_eq!(line, Some(2));
+        }
+        _ => panic!("Expected a parse error"),
+    }
+}
+
+#[test]
+fn test_load_tests() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let file = dir.join("tests.sql");
+    fs::write(
+        &file,
+        "ASSERT (SELECT COUNT(*) FROM orders) > 0 AS 'Orders exist';\n\
+         ASSERT (SELECT MIN(amount) FROM orders) >= 0;\n\
+         DELETE FROM orders;\n\
+         COMMENT ON TABLE orders IS 'Checked'",
+    )
+    .unwrap();
+    let file = file.to_str().unwrap().to_string();
+
+    let (tests, warnings) = load_tests(&[file.clone()], &template::Context::default()).unwrap();
+
+    let messages: Vec<_> = tests.iter().map(|x| x.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec!["Orders exist", "(SELECT MIN(amount) FROM orders) >= 0"]
+    );
+    assert_eq!(
+        warnings,
+        vec![
+            Warning {
+                file: file.clone(),
+                line: Some(3),
+                message: "Only ASSERT statements are supported in test files, skipped".to_string(),
+            },
+            Warning {
+                file,
+                line: Some(4),
+                message: "Only ASSERT statements are supported in test files, skipped".to_string(),
+            }
+        ]
+    );
+}
@@ -0,0 +1,143 @@
+use super::error::PowerSqlError;
+use serde_derive::Deserialize;
+use std::env;
+use std::fmt;
+
+/// Connection settings of a target in the `[profiles]` section of `powersql.toml`.
+/// Settings that are not given are read from the environment variables of the backend.
+#[derive(Deserialize, Default, Clone, PartialEq)]
+pub struct Profile {
+    pub backend: Option<String>,
+    // Schema (dataset in BigQuery) of the models named without schema
+    pub schema: Option<String>,
+    // PostgreSQL
+    pub host: Option<String>,
+    pub port: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub database: Option<String>,
+    // BigQuery, `credentials` is the service account key file
+    pub project: Option<String>,
+    pub credentials: Option<String>,
+    pub location: Option<String>,
+    // SQLite database file
+    pub path: Option<String>,
+}
+
+/// Replaces every `${NAME}` in the value by the variable `NAME`
+fn interpolate(value: &str, var: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut res = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        res.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Missing }} after ${{ in {}", value))?;
+        let name = &rest[start + 2..start + end];
+        let x = var(name).ok_or_else(|| format!("Environment variable {} not set", name))?;
+        res.push_str(&x);
+        rest = &rest[start + end + 1..];
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+impl Profile {
+    /// Profile with a backend name only, all settings are read from the environment
+    pub fn for_backend(backend: &str) -> Profile {
+        Profile {
+            backend: Some(backend.to_string()),
+            ..Profile::default()
+        }
+    }
+
+    fn map_settings(&self, f: impl Fn(&str) -> Result<String, String>) -> Result<Profile, String> {
+        let map = |x: &Option<String>| x.as_deref().map(&f).transpose();
+        Ok(Profile {
+            backend: map(&self.backend)?,
+            schema: map(&self.schema)?,
+            host: map(&self.host)?,
+            port: map(&self.port)?,
+            user: map(&self.user)?,
+            password: map(&self.password)?,
+            database: map(&self.database)?,
+            project: map(&self.project)?,
+            credentials: map(&self.credentials)?,
+            location: map(&self.location)?,
+            path: map(&self.path)?,
+        })
+    }
+
+    /// Profile with the environment variables in the settings replaced by their values
+    pub fn interpolated(&self, name: &str) -> Result<Profile, PowerSqlError> {
+        self.map_settings(|x| interpolate(x, &|var| env::var(var).ok()))
+            .map_err(|err| PowerSqlError::config(format!("{} in profile {}", err, name)))
+    }
+}
+
+// The password is left out of debug output
+impl fmt::Debug for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Profile")
+            .field("backend", &self.backend)
+            .field("schema", &self.schema)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("database", &self.database)
+            .field("project", &self.project)
+            .field("credentials", &self.credentials)
+            .field("location", &self.location)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[test]
+fn test_interpolate() {
+    let var = |name: &str| match name {
+        "USER" => Some("alice".to_string()),
+        _ => None,
+    };
+
+    assert_eq!(
+        interpolate("dev_${USER}", &var),
+        Ok("dev_alice".to_string())
+    );
+    assert_eq!(
+        interpolate("${USER}/${USER}", &var),
+        Ok("alice/alice".to_string())
+    );
+    assert_eq!(interpolate("$USER", &var), Ok("$USER".to_string()));
+    assert_eq!(
+        interpolate("${PASSWORD}", &var),
+        Err("Environment variable PASSWORD not set".to_string())
+    );
+    assert!(interpolate("dev_${USER", &var).is_err());
+
+    let profile = Profile {
+        schema: Some("dev_${USER}".to_string()),
+        ..Profile::for_backend("postgres")
+    };
+    assert_eq!(
+        profile.map_settings(|x| interpolate(x, &var)),
+        Ok(Profile {
+            schema: Some("dev_alice".to_string()),
+            ..Profile::for_backend("postgres")
+        })
+    );
+}
+
+#[test]
+fn test_debug_redacts_password() {
+    let profile = Profile {
+        user: Some("alice".to_string()),
+        password: Some("secret".to_string()),
+        ..Profile::for_backend("postgres")
+    };
+    let debug = format!("{:?}", profile);
+    assert!(debug.contains("\"alice\""));
+    assert!(debug.contains("password: Some(\"***\")"));
+    assert!(!debug.contains("secret"));
+}
@@ -0,0 +1,241 @@
+use super::error::PowerSqlError;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::{Component, Path};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Selector {
+    // model, +model, model+ or +model+
+    Model {
+        name: String,
+        ancestors: bool,
+        descendants: bool,
+    },
+    // path:models/finance
+    Path(String),
+}
+
+pub fn parse_selector(selector: &str) -> Result<Selector, PowerSqlError> {
+    if let Some(path) = selector.strip_prefix("path:") {
+        if path.is_empty() {
+            return Err(PowerSqlError::config(format!(
+                "Empty path in selector {}",
+                selector
+            )));
+        }
+        return Ok(Selector::Path(path.to_string()));
+    }
+
+    let ancestors = selector.starts_with('+');
+    let descendants = selector.ends_with('+');
+    let name = selector.trim_start_matches('+').trim_end_matches('+');
+
+    if name.is_empty() || name.contains('+') {
+        return Err(PowerSqlError::config(format!(
+            "Invalid selector {}",
+            selector
+        )));
+    }
+
+    Ok(Selector::Model {
+        name: name.to_string(),
+        ancestors,
+        descendants,
+    })
+}
+
+/// Components of the path without `.`, so `./models` and `models` are the same
+pub fn normal_components(path: &Path) -> Vec<Component> {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+pub fn in_directory(file: &str, dir: &str) -> bool {
+    let file = normal_components(Path::new(file));
+    let dir = normal_components(Path::new(dir));
+
+    file.starts_with(&dir)
+}
+
+fn collect_reachable(
+    start: &str,
+    edges: &HashMap<String, Vec<String>>,
+    selected: &mut HashSet<String>,
+) {
+    let mut stack = vec![start.to_string()];
+    while let Some(x) = stack.pop() {
+        if let Some(next) = edges.get(&x) {
+            for n in next {
+                if selected.insert(n.clone()) {
+                    stack.push(n.clone());
+                }
+            }
+        }
+    }
+}
+
+fn resolve_selector(
+    selector: &Selector,
+    deps: &HashMap<String, Vec<String>>,
+    dependents: &HashMap<String, Vec<String>>,
+    paths: &HashMap<String, String>,
+) -> Result<HashSet<String>, PowerSqlError> {
+    let mut selected = HashSet::new();
+    match selector {
+        Selector::Model {
+            name,
+            ancestors,
+            descendants,
+        } => {
+            if !deps.contains_key(name) {
+                return Err(PowerSqlError::MissingModel {
+                    model: name.clone(),
+                });
+            }
+            selected.insert(name.clone());
+            if *ancestors {
+                collect_reachable(name, deps, &mut selected);
+            }
+            if *descendants {
+                collect_reachable(name, dependents, &mut selected);
+            }
+        }
+        Selector::Path(dir) => {
+            selected.extend(
+                paths
+                    .iter()
+                    .filter(|(m, path)| deps.contains_key(*m) && in_directory(path, dir))
+                    .map(|(m, _)| m.clone()),
+            );
+        }
+    }
+    Ok(selected)
+}
+
+/// Returns the models matched by `select` (all models when empty) minus those matched by `exclude`
+pub fn select_models(
+    select: &[String],
+    exclude: &[String],
+    deps: &HashMap<String, Vec<String>>,
+    paths: &HashMap<String, String>,
+) -> Result<HashSet<String>, PowerSqlError> {
+    let mut dependents: HashMap<String, Vec<String>> = HashMap::new();
+    for (model, model_deps) in deps.iter() {
+        for d in model_deps {
+            dependents.entry(d.clone()).or_default().push(model.clone());
+        }
+    }
+
+    let mut selected = HashSet::new();
+    if select.is_empty() {
+        selected.extend(deps.keys().cloned());
+    }
+    for s in select {
+        let selector = parse_selector(s)?;
+        selected.extend(resolve_selector(&selector, deps, &dependents, paths)?);
+    }
+    for s in exclude {
+        let selector = parse_selector(s)?;
+        for m in resolve_selector(&selector, deps, &dependents, paths)? {
+            selected.remove(&m);
+        }
+    }
+    Ok(selected)
+}
+
+/// Restricts the dependency graph to the selected models
+pub fn filter_dependencies(
+    deps: &HashMap<String, Vec<String>>,
+    selected: &HashSet<String>,
+) -> HashMap<String, Vec<String>> {
+    deps.iter()
+        .filter(|(model, _)| selected.contains(*model))
+        .map(|(model, model_deps)| {
+            (
+                model.clone(),
+                model_deps
+                    .iter()
+                    .filter(|d| selected.contains(*d))
+                    .cloned()
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn test_deps() -> HashMap<String, Vec<String>> {
+    hashmap! {
+        "a".to_string() => vec!["b".to_string()],
+        "b".to_string() => vec!["c".to_string()],
+        "c".to_string() => vec![],
+        "d".to_string() => vec!["b".to_string()],
+    }
+}
+
+#[test]
+fn test_parse_selector() {
+    assert_eq!(
+        parse_selector("+a+"),
+        Ok(Selector::Model {
+            name: "a".to_string(),
+            ancestors: true,
+            descendants: true
+        })
+    );
+    assert_eq!(
+        parse_selector("path:models/finance"),
+        Ok(Selector::Path("models/finance".to_string()))
+    );
+    assert!(parse_selector("+").is_err());
+    assert!(parse_selector("a+b").is_err());
+}
+
+#[test]
+fn test_select_ancestors_and_descendants() {
+    let deps = test_deps();
+    let paths = HashMap::new();
+
+    let ancestors = select_models(&["+b".to_string()], &[], &deps, &paths).unwrap();
+    assert_eq!(ancestors, hashset! {"b".to_string(), "c".to_string()});
+
+    let descendants = select_models(&["b+".to_string()], &[], &deps, &paths).unwrap();
+    assert_eq!(
+        descendants,
+        hashset! {"a".to_string(), "b".to_string(), "d".to_string()}
+    );
+}
+
+#[test]
+fn test_select_path_and_exclude() {
+    let deps = test_deps();
+    let paths = hashmap! {
+        "a".to_string() => "models/finance/a.sql".to_string(),
+        "b".to_string() => "models/finance/b.sql".to_string(),
+        "c".to_string() => "models/c.sql".to_string(),
+        "d".to_string() => "./models/finance_old/d.sql".to_string(),
+    };
+
+    let selected = select_models(
+        &["path:./models/finance".to_string()],
+        &["a".to_string()],
+        &deps,
+        &paths,
+    )
+    .unwrap();
+    assert_eq!(selected, hashset! {"b".to_string()});
+
+    assert_eq!(
+        filter_dependencies(&deps, &hashset! {"a".to_string(), "b".to_string()}),
+        hashmap! {
+            "a".to_string() => vec!["b".to_string()],
+            "b".to_string() => vec![],
+        }
+    );
+}
+
+#[test]
+fn test_select_unknown_model() {
+    assert!(select_models(&["x+".to_string()], &[], &test_deps(), &HashMap::new()).is_err());
+}
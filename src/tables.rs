@@ -0,0 +1,243 @@
+use sqlparser::ast::{
+    Expr, Function, Join, JoinConstraint, JoinOperator, ListAgg, ObjectName, OrderByExpr, Query,
+    SelectItem, SetExpr, Statement, TableFactor,
+};
+
+/// Query of a model or test statement
+pub fn get_query(statement: &Statement) -> &Query {
+    match statement {
+        Statement::CreateView { query, .. } => query,
+        Statement::CreateTable {
+            query: Some(query), ..
+        } => query,
+        Statement::Query(query) => query,
+        _ => unreachable!("Expected view, table, of query in fn get_query"),
+    }
+}
+
+/// Calls `f` with the name of every table in the query, except the CTEs in scope
+fn visit_tables_table_factor(
+    table_factor: &mut TableFactor,
+    ctes: &im::HashSet<String>,
+    f: &mut dyn FnMut(&mut ObjectName),
+) {
+    match table_factor {
+        TableFactor::Table { name, args, .. } => {
+            if !ctes.contains(&name.to_string()) {
+                f(name);
+            }
+            for arg in args {
+                visit_tables_expr(arg, ctes, f);
+            }
+        }
+        TableFactor::NestedJoin(nested_join) => {
+            visit_tables_table_factor(&mut nested_join.relation, ctes, f);
+
+            for join in nested_join.joins.iter_mut() {
+                visit_tables_join(join, ctes, f);
+            }
+        }
+        TableFactor::Derived {
+            subquery: query, ..
+        } => {
+            visit_tables_query(query, ctes, f);
+        }
+    }
+}
+
+fn visit_tables_join(
+    join: &mut Join,
+    ctes: &im::HashSet<String>,
+    f: &mut dyn FnMut(&mut ObjectName),
+) {
+    visit_tables_table_factor(&mut join.relation, ctes, f);
+
+    match &mut join.join_operator {
+        JoinOperator::Inner(JoinConstraint::On(expr))
+        | JoinOperator::LeftOuter(JoinConstraint::On(expr))
+        | JoinOperator::RightOuter(JoinConstraint::On(expr))
+        | JoinOperator::FullOuter(JoinConstraint::On(expr)) => visit_tables_expr(expr, ctes, f),
+        _ => {}
+    }
+}
+
+fn visit_tables_order_by(
+    order_by: &mut [OrderByExpr],
+    ctes: &im::HashSet<String>,
+    f: &mut dyn FnMut(&mut ObjectName),
+) {
+    for x in order_by {
+        visit_tables_expr(&mut x.expr, ctes, f);
+    }
+}
+
+fn visit_tables_set_expr(
+    body: &mut SetExpr,
+    ctes: &im::HashSet<String>,
+    f: &mut dyn FnMut(&mut ObjectName),
+) {
+    match body {
+        SetExpr::Query(q) => visit_tables_query(q, ctes, f),
+        SetExpr::Select(select) => {
+            for table in select.from.iter_mut() {
+                visit_tables_table_factor(&mut table.relation, ctes, f);
+                for join in table.joins.iter_mut() {
+                    visit_tables_join(join, ctes, f);
+                }
+            }
+
+            select.projection.iter_mut().for_each(|x| match x {
+                SelectItem::ExprWithAlias { expr, .. } => visit_tables_expr(expr, ctes, f),
+                SelectItem::UnnamedExpr(expr) => visit_tables_expr(expr, ctes, f),
+                _ => {}
+            });
+
+            if let Some(selection) = &mut select.selection {
+                visit_tables_expr(selection, ctes, f);
+            }
+            for expr in select.group_by.iter_mut() {
+                visit_tables_expr(expr, ctes, f);
+            }
+            if let Some(having) = &mut select.having {
+                visit_tables_expr(having, ctes, f);
+            }
+        }
+        SetExpr::SetOperation { left, right, .. } => {
+            visit_tables_set_expr(left, ctes, f);
+            visit_tables_set_expr(right, ctes, f);
+        }
+        SetExpr::Values(values) => {
+            for row in values.0.iter_mut() {
+                for expr in row {
+                    visit_tables_expr(expr, ctes, f);
+                }
+            }
+        }
+    }
+}
+
+pub fn visit_tables_query(
+    query: &mut Query,
+    ctes: &im::HashSet<String>,
+    f: &mut dyn FnMut(&mut ObjectName),
+) {
+    // CTEs shadow models with the same name in the rest of the query
+    let mut ctes = ctes.clone();
+    for cte in query.ctes.iter_mut() {
+        visit_tables_query(&mut cte.query, &ctes, f);
+        ctes.insert(cte.alias.name.to_string());
+    }
+    let ctes = &ctes;
+    visit_tables_set_expr(&mut query.body, ctes, f);
+    visit_tables_order_by(&mut query.order_by, ctes, f);
+    if let Some(limit) = &mut query.limit {
+        visit_tables_expr(limit, ctes, f);
+    }
+}
+
+/// Name made of the identifier values without their quotes, the key of sources
+pub fn unquoted_name(name: &ObjectName) -> String {
+    name.0
+        .iter()
+        .map(|x| x.value.as_str())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Names of the tables the query reads from, in order of appearance
+pub fn get_refs(query: &Query, vec: &mut Vec<String>) {
+    let mut query = query.clone();
+    visit_tables_query(&mut query, &im::HashSet::new(), &mut |name| {
+        vec.push(name.to_string())
+    });
+}
+
+pub fn get_refs_expr(expr: &Expr, vec: &mut Vec<String>) {
+    let mut expr = expr.clone();
+    visit_tables_expr(&mut expr, &im::HashSet::new(), &mut |name| {
+        vec.push(name.to_string())
+    });
+}
+
+pub fn visit_tables_expr(
+    expr: &mut Expr,
+    ctes: &im::HashSet<String>,
+    f: &mut dyn FnMut(&mut ObjectName),
+) {
+    match expr {
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            visit_tables_expr(expr, ctes, f);
+            visit_tables_expr(low, ctes, f);
+            visit_tables_expr(high, ctes, f);
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            visit_tables_expr(left, ctes, f);
+            visit_tables_expr(right, ctes, f);
+        }
+        Expr::Cast { expr, .. } => {
+            visit_tables_expr(expr, ctes, f);
+        }
+        Expr::Collate { expr, .. } => {
+            visit_tables_expr(expr, ctes, f);
+        }
+        Expr::Exists(query) => visit_tables_query(query, ctes, f),
+        Expr::Extract { expr, .. } => visit_tables_expr(expr, ctes, f),
+        Expr::Function(Function { args, over, .. }) => {
+            for arg in args {
+                visit_tables_expr(arg, ctes, f);
+            }
+            if let Some(over) = over {
+                for expr in over.partition_by.iter_mut() {
+                    visit_tables_expr(expr, ctes, f);
+                }
+                visit_tables_order_by(&mut over.order_by, ctes, f);
+            }
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            if let Some(operand) = operand {
+                visit_tables_expr(operand, ctes, f);
+            }
+            for expr in conditions.iter_mut().chain(results.iter_mut()) {
+                visit_tables_expr(expr, ctes, f);
+            }
+            if let Some(else_result) = else_result {
+                visit_tables_expr(else_result, ctes, f);
+            }
+        }
+        Expr::InList { expr, list, .. } => {
+            visit_tables_expr(expr, ctes, f);
+            for expr in list {
+                visit_tables_expr(expr, ctes, f);
+            }
+        }
+        Expr::InSubquery { expr, subquery, .. } => {
+            visit_tables_expr(expr, ctes, f);
+            visit_tables_query(subquery, ctes, f);
+        }
+        Expr::IsNotNull(expr) => {
+            visit_tables_expr(expr, ctes, f);
+        }
+        Expr::IsNull(expr) => {
+            visit_tables_expr(expr, ctes, f);
+        }
+        Expr::ListAgg(ListAgg {
+            expr, within_group, ..
+        }) => {
+            visit_tables_expr(expr, ctes, f);
+            visit_tables_order_by(within_group, ctes, f);
+        }
+        Expr::Nested(expr) => {
+            visit_tables_expr(expr, ctes, f);
+        }
+        Expr::Subquery(query) => visit_tables_query(query, ctes, f),
+        Expr::UnaryOp { expr, .. } => visit_tables_expr(expr, ctes, f),
+        _ => {}
+    }
+}
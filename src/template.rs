@@ -0,0 +1,552 @@
+use super::error::PowerSqlError;
+use super::macros::Macro;
+use std::collections::HashMap;
+use std::env;
+use std::ops::Range;
+
+// Macros expanding to themselves are reported after this many nested expansions
+const MAX_DEPTH: usize = 32;
+
+/// Values available to the template expressions
+#[derive(Debug, Default, Clone)]
+pub struct Context {
+    pub vars: HashMap<String, String>,
+    pub macros: HashMap<String, Macro>,
+}
+
+// Start of a part of the rendered SQL, with the offset in the original file
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    rendered: usize,
+    original: usize,
+    // The part is the value of a template expression instead of a copy of the file
+    expression: bool,
+}
+
+/// SQL file with the template expressions replaced by their value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rendered {
+    pub sql: String,
+    // Models referred to with `ref`
+    pub refs: Vec<String>,
+    source: String,
+    segments: Vec<Segment>,
+}
+
+/// Byte offset of a line and column, both starting at 1
+fn offset(text: &str, line: u64, column: u64) -> usize {
+    let line_start: usize = text
+        .split('\n')
+        .take(line.saturating_sub(1) as usize)
+        .map(|x| x.len() + 1)
+        .sum();
+    let line_start = line_start.min(text.len());
+    text[line_start..]
+        .char_indices()
+        .nth(column.saturating_sub(1) as usize)
+        .map_or(text.len(), |(i, _)| line_start + i)
+}
+
+/// The `$tag$` opening a dollar quoted string at the start of the text, `$1` is a parameter
+fn dollar_tag(text: &str) -> Option<&str> {
+    let rest = text.strip_prefix('$')?;
+    let len = rest
+        .find(|c: char| !c.is_alphanumeric() && c != '_')
+        .unwrap_or(rest.len());
+    match (rest[..len].chars().next(), rest[len..].starts_with('$')) {
+        (Some(first), _) if first.is_ascii_digit() => None,
+        (_, true) => Some(&text[..len + 2]),
+        _ => None,
+    }
+}
+
+/// Line and column of a byte offset, both starting at 1
+pub fn position(text: &str, offset: usize) -> (u64, u64) {
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line as u64, column as u64)
+}
+
+fn error(file: &str, source: &str, offset: usize, message: impl Into<String>) -> PowerSqlError {
+    let (line, column) = position(source, offset);
+    PowerSqlError::Parse {
+        file: file.to_string(),
+        line: Some(line),
+        column: Some(column),
+        message: message.into(),
+    }
+}
+
+/// Splits arguments on the commas outside of parentheses and strings
+fn split_args(args: &str) -> Vec<&str> {
+    let mut res = vec![];
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                res.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !args[start..].trim().is_empty() || !res.is_empty() {
+        res.push(args[start..].trim());
+    }
+    res
+}
+
+/// Parses a call like `var('start_date')` into the name and the arguments
+fn parse_call(expr: &str) -> Result<(&str, Vec<&str>), String> {
+    let expr = expr.trim();
+    match expr.find('(') {
+        Some(open) if expr.ends_with(')') => Ok((
+            expr[..open].trim(),
+            split_args(&expr[open + 1..expr.len() - 1]),
+        )),
+        _ => Err(format!("Expected a call like var('name'), got {}", expr)),
+    }
+}
+
+/// Value of a quoted string argument
+fn string_arg<'a>(function: &str, arg: &'a str) -> Result<&'a str, String> {
+    let quoted = arg.len() >= 2
+        && ((arg.starts_with('\'') && arg.ends_with('\''))
+            || (arg.starts_with('"') && arg.ends_with('"')));
+    if quoted {
+        Ok(&arg[1..arg.len() - 1])
+    } else {
+        Err(format!(
+            "Expected a quoted string as argument of {}, got {}",
+            function, arg
+        ))
+    }
+}
+
+impl Rendered {
+    fn call(&mut self, expr: &str, context: &Context, depth: usize) -> Result<String, String> {
+        let (name, args) = parse_call(expr)?;
+        if let Some(m) = context.macros.get(name) {
+            return self.expand(name, m, &args, context, depth);
+        }
+
+        let vars = &context.vars;
+        let args = args
+            .iter()
+            .map(|arg| string_arg(name, arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        match (name, args.as_slice()) {
+            ("var", [var]) => vars
+                .get(*var)
+                .cloned()
+                .ok_or_else(|| format!("Variable {} not defined in [vars] or --vars", var)),
+            ("var", [var, default]) => Ok(vars
+                .get(*var)
+                .cloned()
+                .unwrap_or_else(|| default.to_string())),
+            ("env_var", [var]) => {
+                env::var(var).map_err(|_| format!("Environment variable {} not set", var))
+            }
+            ("env_var", [var, default]) => {
+                Ok(env::var(var).unwrap_or_else(|_| default.to_string()))
+            }
+            ("ref", [model]) => {
+                self.refs.push(model.to_string());
+                Ok(model.to_string())
+            }
+            ("var", _) | ("env_var", _) | ("ref", _) => Err(format!(
+                "Wrong number of arguments for {}, got {}",
+                name,
+                args.len()
+            )),
+            _ => Err(format!("Unknown template function or macro {}", name)),
+        }
+    }
+
+    fn expand(
+        &mut self,
+        name: &str,
+        m: &Macro,
+        args: &[&str],
+        context: &Context,
+        depth: usize,
+    ) -> Result<String, String> {
+        if args.len() != m.params.len() {
+            return Err(format!(
+                "Macro {} expects {} arguments, got {}",
+                name,
+                m.params.len(),
+                args.len()
+            ));
+        }
+        if depth >= MAX_DEPTH {
+            return Err(format!("Macro {} expands recursively", name));
+        }
+        // Errors in the body are reported at the call of the macro
+        let body = m.substitute(args);
+        let rendered = render_nested("", &body, context, depth + 1).map_err(|err| match err {
+            PowerSqlError::Parse { message, .. } if message.contains(" in macro ") => message,
+            PowerSqlError::Parse { message, .. } => format!("{} in macro {}", message, name),
+            err => err.to_string(),
+        })?;
+        self.refs.extend(rendered.refs);
+        Ok(rendered.sql)
+    }
+
+    fn push(&mut self, original: usize, text: &str, expression: bool) {
+        self.segments.push(Segment {
+            rendered: self.sql.len(),
+            original,
+            expression,
+        });
+        self.sql.push_str(text);
+    }
+
+    /// Offset in the original file of an offset in the rendered SQL.
+    /// Positions in the value of an expression are at the start of the expression.
+    fn original_offset(&self, offset: usize) -> usize {
+        match self.segments.iter().rev().find(|x| x.rendered <= offset) {
+            Some(segment) if segment.expression => segment.original,
+            Some(segment) => (segment.original + offset - segment.rendered).min(self.source.len()),
+            None => 0,
+        }
+    }
+
+    /// Byte ranges of the statements in the rendered SQL, without the `;`
+    pub fn statements(&self) -> Vec<Range<usize>> {
+        let mut ranges = vec![];
+        let mut start = None;
+        let mut chars = self.sql.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c == '-' && chars.peek().map(|x| x.1) == Some('-') {
+                chars.find(|x| x.1 == '\n');
+                continue;
+            }
+            if c == '/' && chars.peek().map(|x| x.1) == Some('*') {
+                chars.next();
+                let mut previous = ' ';
+                chars.find(|x| {
+                    let end = previous == '*' && x.1 == '/';
+                    previous = x.1;
+                    end
+                });
+                continue;
+            }
+            if c == ';' {
+                ranges.extend(start.take().map(|start| start..i));
+                continue;
+            }
+            if start.is_none() && !c.is_whitespace() {
+                start = Some(i);
+            }
+            if c == '\'' || c == '"' || c == '`' {
+                chars.find(|x| x.1 == c);
+            }
+            // PostgreSQL dollar quoting, like $$ ... $$ or $body$ ... $body$
+            if let Some(tag) = dollar_tag(&self.sql[i..]) {
+                let end = self.sql[i + tag.len()..]
+                    .find(tag)
+                    .map_or(self.sql.len(), |x| i + 2 * tag.len() + x);
+                while chars.peek().map_or(false, |x| x.0 < end) {
+                    chars.next();
+                }
+            }
+        }
+        ranges.extend(start.map(|start| start..self.sql.len()));
+        ranges
+    }
+
+    /// Line in the original file of an offset in the rendered SQL
+    pub fn line(&self, offset: usize) -> u64 {
+        position(&self.source, self.original_offset(offset)).0
+    }
+
+    /// Moves the position of a parse error in the statement starting at `start` in the
+    /// rendered SQL to the original file
+    pub fn map_error(&self, start: usize, err: PowerSqlError) -> PowerSqlError {
+        match err {
+            PowerSqlError::Parse {
+                file,
+                line: Some(line),
+                column: Some(column),
+                message,
+            } => {
+                let original =
+                    self.original_offset(start + offset(&self.sql[start..], line, column));
+                let (new_line, new_column) = position(&self.source, original);
+                PowerSqlError::Parse {
+                    file,
+                    line: Some(new_line),
+                    column: Some(new_column),
+                    message: message.replace(
+                        &format!("Line: {}, Column {}", line, column),
+                        &format!("Line: {}, Column {}", new_line, new_column),
+                    ),
+                }
+            }
+            err => err,
+        }
+    }
+}
+
+/// Replaces the `{{ ... }}` expressions in a SQL file by their value:
+/// `var('name')` and `var('name', 'default')` give a variable, `env_var('NAME')`
+/// an environment variable, `ref('model')` the name of a model and
+/// `name(arg, ...)` the body of a macro.
+pub fn render(file: &str, source: &str, context: &Context) -> Result<Rendered, PowerSqlError> {
+    render_nested(file, source, context, 0)
+}
+
+fn render_nested(
+    file: &str,
+    source: &str,
+    context: &Context,
+    depth: usize,
+) -> Result<Rendered, PowerSqlError> {
+    let mut rendered = Rendered {
+        sql: String::new(),
+        refs: vec![],
+        source: source.to_string(),
+        segments: vec![],
+    };
+    let mut start = 0;
+    while let Some(open) = source[start..].find("{{").map(|x| x + start) {
+        rendered.push(start, &source[start..open], false);
+        let close = source[open..]
+            .find("}}")
+            .map(|x| x + open)
+            .ok_or_else(|| error(file, source, open, "Missing }} after {{"))?;
+        let value = rendered
+            .call(&source[open + 2..close], context, depth)
+            .map_err(|message| error(file, source, open, message))?;
+        rendered.push(open, &value, true);
+        start = close + 2;
+    }
+    rendered.push(start, &source[start..], false);
+    Ok(rendered)
+}
+
+#[test]
+fn test_render() {
+    let context = Context {
+        vars: hashmap! {"start_date".to_string() => "2020-01-01".to_string()},
+        ..Context::default()
+    };
+    let sql = "CREATE VIEW x AS SELECT * FROM {{ ref('orders') }}\n\
+               WHERE date >= '{{ var(\"start_date\") }}' AND n < {{ var('limit', '10') }}";
+
+    let rendered = render("x.sql", sql, &context).unwrap();
+
+    assert_eq!(
+        rendered.sql,
+        "CREATE VIEW x AS SELECT * FROM orders\nWHERE date >= '2020-01-01' AND n < 10"
+    );
+    assert_eq!(rendered.refs, vec!["orders"]);
+    assert_eq!(
+        render("x.sql", "SELECT 1", &context).unwrap().sql,
+        "SELECT 1"
+    );
+}
+
+#[test]
+fn test_render_errors() {
+    let context = Context::default();
+    let cases = vec![
+        (
+            "SELECT\n  {{ var('end_date') }}",
+            (2, 3),
+            "Variable end_date not defined in [vars] or --vars",
+        ),
+        ("SELECT {{ var('a'", (1, 8), "Missing }} after {{"),
+        (
+            "SELECT {{ var(a) }}",
+            (1, 8),
+            "Expected a quoted string as argument of var, got a",
+        ),
+        (
+            "SELECT {{ now() }}",
+            (1, 8),
+            "Unknown template function or macro now",
+        ),
+        (
+            "SELECT {{ ref('a', 'b') }}",
+            (1, 8),
+            "Wrong number of arguments for ref, got 2",
+        ),
+    ];
+    for (sql, (line, column), message) in cases {
+        assert_eq!(
+            render("x.sql", sql, &context),
+            Err(PowerSqlError::Parse {
+                file: "x.sql".to_string(),
+                line: Some(line),
+                column: Some(column),
+                message: message.to_string(),
+            }),
+            "{}",
+            sql
+        );
+    }
+}
+
+#[test]
+fn test_map_error() {
+    let context = Context {
+        vars: hashmap! {"table".to_string() => "a_long_table_name".to_string()},
+        ..Context::default()
+    };
+    let rendered = render(
+        "x.sql",
+        "SELECT * FROM {{ var('table') }}\nWHERE # 1",
+        &context,
+    )
+    .unwrap();
+    let err = PowerSqlError::Parse {
+        file: "x.sql".to_string(),
+        line: Some(2),
+        column: Some(7),
+        message: "Tokenizer Error at Line: 2, Column 7, unhandled char '#'".to_string(),
+    };
+
+    assert_eq!(
+        rendered.map_error(0, err),
+        PowerSqlError::Parse {
+            file: "x.sql".to_string(),
+            line: Some(2),
+            column: Some(7),
+            message: "Tokenizer Error at Line: 2, Column 7, unhandled char '#'".to_string(),
+        }
+    );
+
+    // Positions after an expression on the same line move by the length difference
+    let rendered = render("x.sql", "SELECT {{ var('table') }} # 1", &context).unwrap();
+    let err = PowerSqlError::Parse {
+        file: "x.sql".to_string(),
+        line: Some(1),
+        column: Some(26),
+        message: "Tokenizer Error at Line: 1, Column 26, unhandled char '#'".to_string(),
+    };
+    assert_eq!(
+        rendered.map_error(0, err),
+        PowerSqlError::Parse {
+            file: "x.sql".to_string(),
+            line: Some(1),
+            column: Some(27),
+            message: "Tokenizer Error at Line: 1, Column 27, unhandled char '#'".to_string(),
+        }
+    );
+
+    // Positions in a statement are relative to its start
+    let rendered = render("x.sql", "SELECT 1;\nSELECT # 1", &context).unwrap();
+    let err = PowerSqlError::Parse {
+        file: "x.sql".to_string(),
+        line: Some(1),
+        column: Some(8),
+        message: "Tokenizer Error at Line: 1, Column 8, unhandled char '#'".to_string(),
+    };
+    assert_eq!(
+        rendered.map_error(10, err),
+        PowerSqlError::Parse {
+            file: "x.sql".to_string(),
+            line: Some(2),
+            column: Some(8),
+            message: "Tokenizer Error at Line: 2, Column 8, unhandled char '#'".to_string(),
+        }
+    );
+}
+
+#[test]
+fn test_statements() {
+    let context = Context {
+        vars: hashmap! {"grantee".to_string() => "'a;\nb'".to_string()},
+        ..Context::default()
+    };
+    let rendered = render(
+        "x.sql",
+        "-- Orders; with grants\nCREATE VIEW a AS SELECT ';' AS x;\n\n\
+         INSERT INTO log VALUES ({{ var('grantee') }}); DELETE FROM log;\n\
+         CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql;\n\
+         DO $body$ BEGIN PERFORM f(); END $body$; PREPARE p AS SELECT $1;\n\
+         /* Comments; */ GRANT SELECT ON a TO reporting -- all; users\n",
+        &context,
+    )
+    .unwrap();
+    let statements: Vec<_> = rendered
+        .statements()
+        .into_iter()
+        .map(|x| (rendered.line(x.start), &rendered.sql[x]))
+        .collect();
+
+    assert_eq!(
+        statements,
+        vec![
+            (2, "CREATE VIEW a AS SELECT ';' AS x"),
+            (4, "INSERT INTO log VALUES ('a;\nb')"),
+            (4, "DELETE FROM log"),
+            (
+                5,
+                "CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql"
+            ),
+            (6, "DO $body$ BEGIN PERFORM f(); END $body$"),
+            (6, "PREPARE p AS SELECT $1"),
+            (7, "GRANT SELECT ON a TO reporting -- all; users\n"),
+        ]
+    );
+}
+
+#[test]
+fn test_render_macros() {
+    let macro_sql = "CREATE MACRO to_eur(amount, currency) AS \
+                     amount * (SELECT rate FROM {{ ref('rates') }} r WHERE r.currency = currency);\n\
+                     CREATE MACRO price_eur() AS {{ to_eur(price, 'USD') }};\n\
+                     CREATE MACRO forever(x) AS {{ forever(x) }}";
+    let context = Context {
+        macros: super::macros::parse_macros("macros.sql", macro_sql)
+            .unwrap()
+            .into_iter()
+            .collect(),
+        ..Context::default()
+    };
+
+    let rendered = render("x.sql", "SELECT {{ price_eur() }} FROM orders", &context).unwrap();
+    assert_eq!(
+        rendered.sql,
+        "SELECT price * (SELECT rate FROM rates r WHERE r.currency = 'USD') FROM orders"
+    );
+    assert_eq!(rendered.refs, vec!["rates"]);
+
+    let cases = vec![
+        (
+            "SELECT\n  {{ to_eur(price) }}",
+            (2, 3),
+            "Macro to_eur expects 2 arguments, got 1",
+        ),
+        (
+            "SELECT {{ forever(1) }}",
+            (1, 8),
+            "Macro forever expands recursively in macro forever",
+        ),
+    ];
+    for (sql, (line, column), message) in cases {
+        match render("x.sql", sql, &context) {
+            Err(PowerSqlError::Parse {
+                file,
+                line: Some(l),
+                column: Some(c),
+                message: m,
+            }) => {
+                assert_eq!((file.as_str(), l, c), ("x.sql", line, column), "{}", sql);
+                assert!(m.starts_with(message), "{}", m);
+            }
+            x => panic!("Expected parse error for {}, got {:?}", sql, x),
+        }
+    }
+}
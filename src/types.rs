@@ -1,9 +1,23 @@
+use super::error::PowerSqlError;
+use super::tables::{unquoted_name, visit_tables_query};
+use sqlparser::ast::BinaryOperator;
+use sqlparser::ast::ColumnDef;
+use sqlparser::ast::ColumnOption;
 use sqlparser::ast::DataType;
 use sqlparser::ast::Expr;
+use sqlparser::ast::Function;
+use sqlparser::ast::Join;
+use sqlparser::ast::JoinConstraint;
+use sqlparser::ast::JoinOperator;
+use sqlparser::ast::OrderByExpr;
 use sqlparser::ast::Query;
+use sqlparser::ast::Select;
 use sqlparser::ast::SelectItem;
+use sqlparser::ast::SetExpr;
+use sqlparser::ast::SetOperator;
 use sqlparser::ast::TableFactor;
 use sqlparser::ast::Value;
+use sqlparser::ast::Values;
 use std::collections::HashMap;
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum TableType {
@@ -13,126 +27,919 @@ pub enum TableType {
     Closed(HashMap<String, BaseType>),
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum BaseType {
     Any,
     String,
     Boolean,
+    SmallInt,
+    // INT and integer literals
     Number,
+    BigInt,
+    Decimal {
+        precision: Option<u64>,
+        scale: Option<u64>,
+    },
     Float,
-    // Add all known types
+    Date,
+    Time,
+    Timestamp,
+    Interval,
+    Bytes,
+    Json,
+    Array(Box<BaseType>),
+    // Fields in order, empty when unknown
+    Struct(Vec<(String, BaseType)>),
+    // Value that can be NULL, never wraps Any or another Nullable
+    Nullable(Box<BaseType>),
+}
+
+impl BaseType {
+    /// The type that can also be NULL
+    pub fn nullable(self) -> BaseType {
+        match self {
+            BaseType::Any | BaseType::Nullable(_) => self,
+            ty => BaseType::Nullable(Box::new(ty)),
+        }
+    }
+
+    /// Makes the type nullable when `nullable` is set
+    fn nullable_if(self, nullable: bool) -> BaseType {
+        if nullable {
+            self.nullable()
+        } else {
+            self
+        }
+    }
+
+    pub fn is_nullable(&self) -> bool {
+        match self {
+            BaseType::Nullable(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The type without nullability
+    pub fn non_null(&self) -> &BaseType {
+        match self {
+            BaseType::Nullable(ty) => ty,
+            ty => ty,
+        }
+    }
 }
 
 fn value_type(value: &Value) -> BaseType {
     match value {
         Value::Boolean(_) => BaseType::Boolean,
         Value::SingleQuotedString(_) => BaseType::String,
+        Value::Number(n) if n.contains('.') || n.contains('e') || n.contains('E') => {
+            BaseType::Float
+        }
         Value::Number(_) => BaseType::Number,
+        Value::Interval { .. } => BaseType::Interval,
         // TODO extend
         _ => BaseType::Any,
     }
 }
 
-fn map_data_type(data_type: &DataType) -> BaseType {
+pub fn map_data_type(data_type: &DataType) -> BaseType {
     match data_type {
-        DataType::Float(_) => BaseType::Float,
+        DataType::Char(_) | DataType::Varchar(_) | DataType::Text => BaseType::String,
+        DataType::Float(_) | DataType::Real | DataType::Double => BaseType::Float,
+        DataType::SmallInt => BaseType::SmallInt,
+        DataType::Int => BaseType::Number,
+        DataType::BigInt => BaseType::BigInt,
+        DataType::Decimal(precision, scale) => BaseType::Decimal {
+            precision: *precision,
+            scale: *scale,
+        },
         DataType::Boolean => BaseType::Boolean,
-        DataType::Varchar(_) => BaseType::String,
-        DataType::Text => BaseType::String,
-        // TODO: extend
+        DataType::Date => BaseType::Date,
+        DataType::Time => BaseType::Time,
+        DataType::Timestamp => BaseType::Timestamp,
+        DataType::Interval => BaseType::Interval,
+        DataType::Bytea => BaseType::Bytes,
+        DataType::Array(ty) => BaseType::Array(Box::new(map_data_type(ty))),
+        // BigQuery and PostgreSQL names that are not known to the parser
+        DataType::Custom(name) => match name.to_string().to_uppercase().as_str() {
+            "STRING" => BaseType::String,
+            "BOOL" => BaseType::Boolean,
+            "INT64" => BaseType::BigInt,
+            "FLOAT64" => BaseType::Float,
+            "DATETIME" => BaseType::Timestamp,
+            "BYTES" => BaseType::Bytes,
+            "JSON" | "JSONB" => BaseType::Json,
+            "STRUCT" => BaseType::Struct(vec![]),
+            _ => BaseType::Any,
+        },
         _ => BaseType::Any,
     }
 }
 
+/// Type of a column declared in a `CREATE TABLE` statement
+pub fn column_type(column: &ColumnDef) -> BaseType {
+    let not_null = column.options.iter().any(|option| match option.option {
+        ColumnOption::NotNull => true,
+        ColumnOption::Unique { is_primary } => is_primary,
+        _ => false,
+    });
+    map_data_type(&column.data_type).nullable_if(!not_null)
+}
+
+/// Position of numeric types, values of a type can be converted to the types after it
+fn numeric_rank(ty: &BaseType) -> Option<u8> {
+    match ty {
+        BaseType::SmallInt => Some(0),
+        BaseType::Number => Some(1),
+        BaseType::BigInt => Some(2),
+        BaseType::Decimal { .. } => Some(3),
+        BaseType::Float => Some(4),
+        _ => None,
+    }
+}
+
+fn is_numeric(ty: &BaseType) -> bool {
+    match ty.non_null() {
+        BaseType::Any => true,
+        ty => numeric_rank(ty).is_some(),
+    }
+}
+
+fn is_temporal(ty: &BaseType) -> bool {
+    match ty {
+        BaseType::Date | BaseType::Time | BaseType::Timestamp | BaseType::Interval => true,
+        _ => false,
+    }
+}
+
+/// Common type of two expressions, e.g. of the branches of a `CASE`
+fn unify(a: &BaseType, b: &BaseType) -> Result<BaseType, PowerSqlError> {
+    let nullable = a.is_nullable() || b.is_nullable();
+    let ty = match (a.non_null(), b.non_null()) {
+        (BaseType::Any, _) | (_, BaseType::Any) => return Ok(BaseType::Any),
+        (a, b) if a == b => a.clone(),
+        (BaseType::Decimal { .. }, BaseType::Decimal { .. }) => BaseType::Decimal {
+            precision: None,
+            scale: None,
+        },
+        (BaseType::Date, BaseType::Timestamp) | (BaseType::Timestamp, BaseType::Date) => {
+            BaseType::Timestamp
+        }
+        (BaseType::Array(a), BaseType::Array(b)) => BaseType::Array(Box::new(unify(a, b)?)),
+        (a, b) => match (numeric_rank(a), numeric_rank(b)) {
+            (Some(x), Some(y)) if x >= y => a.clone(),
+            (Some(_), Some(_)) => b.clone(),
+            _ => {
+                return Err(PowerSqlError::type_error(
+                    None,
+                    format!("Could not unify {:?} with {:?}", a, b),
+                ))
+            }
+        },
+    };
+    Ok(ty.nullable_if(nullable))
+}
+
+fn unify_all(tys: &[BaseType]) -> Result<BaseType, PowerSqlError> {
+    tys.iter()
+        .skip(1)
+        .try_fold(tys.get(0).cloned().unwrap_or(BaseType::Any), |a, b| {
+            unify(&a, b)
+        })
+}
+
+/// Whether values of the types can be compared with each other.
+/// String literals are converted to dates and times.
+fn comparable(a: &BaseType, b: &BaseType) -> bool {
+    match (a.non_null(), b.non_null()) {
+        (BaseType::String, ty) | (ty, BaseType::String) if is_temporal(ty) => true,
+        (a, b) => unify(a, b).is_ok(),
+    }
+}
+
+fn is_arithmetic(op: &BinaryOperator) -> bool {
+    match op {
+        BinaryOperator::Plus
+        | BinaryOperator::Minus
+        | BinaryOperator::Multiply
+        | BinaryOperator::Divide
+        | BinaryOperator::Modulus => true,
+        _ => false,
+    }
+}
+
+fn is_comparison(op: &BinaryOperator) -> bool {
+    match op {
+        BinaryOperator::Gt
+        | BinaryOperator::Lt
+        | BinaryOperator::GtEq
+        | BinaryOperator::LtEq
+        | BinaryOperator::Eq
+        | BinaryOperator::NotEq => true,
+        _ => false,
+    }
+}
+
+fn binary_op_type(
+    left: &BaseType,
+    op: &BinaryOperator,
+    right: &BaseType,
+) -> Result<BaseType, PowerSqlError> {
+    let nullable = left.is_nullable() || right.is_nullable();
+    let res = match (left.non_null(), op, right.non_null()) {
+        (BaseType::Any, _, _) | (_, _, BaseType::Any) => match op {
+            op if is_comparison(op) => Some(BaseType::Boolean),
+            BinaryOperator::And
+            | BinaryOperator::Or
+            | BinaryOperator::Like
+            | BinaryOperator::NotLike => Some(BaseType::Boolean),
+            BinaryOperator::StringConcat => Some(BaseType::String),
+            _ => Some(BaseType::Any),
+        },
+        (left, op, right) if is_arithmetic(op) && is_numeric(left) && is_numeric(right) => {
+            Some(unify(left, right)?)
+        }
+        // Date and time arithmetic
+        (BaseType::Date, BinaryOperator::Plus, BaseType::Number)
+        | (BaseType::Date, BinaryOperator::Minus, BaseType::Number) => Some(BaseType::Date),
+        (BaseType::Date, BinaryOperator::Minus, BaseType::Date) => Some(BaseType::Number),
+        (BaseType::Date, BinaryOperator::Plus, BaseType::Interval)
+        | (BaseType::Date, BinaryOperator::Minus, BaseType::Interval)
+        | (BaseType::Timestamp, BinaryOperator::Plus, BaseType::Interval)
+        | (BaseType::Timestamp, BinaryOperator::Minus, BaseType::Interval) => {
+            Some(BaseType::Timestamp)
+        }
+        (BaseType::Time, BinaryOperator::Plus, BaseType::Interval)
+        | (BaseType::Time, BinaryOperator::Minus, BaseType::Interval) => Some(BaseType::Time),
+        (BaseType::Timestamp, BinaryOperator::Minus, BaseType::Timestamp)
+        | (BaseType::Interval, BinaryOperator::Plus, BaseType::Interval)
+        | (BaseType::Interval, BinaryOperator::Minus, BaseType::Interval) => {
+            Some(BaseType::Interval)
+        }
+        (left, op, right) if is_comparison(op) && comparable(left, right) => {
+            Some(BaseType::Boolean)
+        }
+        (BaseType::Boolean, BinaryOperator::And, BaseType::Boolean)
+        | (BaseType::Boolean, BinaryOperator::Or, BaseType::Boolean)
+        | (BaseType::String, BinaryOperator::Like, BaseType::String)
+        | (BaseType::String, BinaryOperator::NotLike, BaseType::String) => Some(BaseType::Boolean),
+        // Values of other types are converted to text
+        (_, BinaryOperator::StringConcat, _) => Some(BaseType::String),
+        _ => None,
+    };
+    match res {
+        Some(ty) => Ok(ty.nullable_if(nullable)),
+        None => Err(PowerSqlError::type_error(
+            None,
+            format!("Could not combine {:?} with {:?} using {}", left, right, op),
+        )),
+    }
+}
+
+/// Return type of a function given the types of its arguments.
+/// Unknown functions have type `Any`.
+fn function_type(name: &str, args: &[BaseType]) -> Result<BaseType, PowerSqlError> {
+    let first = args.get(0).cloned().unwrap_or(BaseType::Any);
+    let nullable = args.iter().any(|ty| ty.is_nullable());
+    let expect = |valid: bool, expected: &str| {
+        if valid {
+            Ok(())
+        } else {
+            Err(PowerSqlError::type_error(
+                None,
+                format!("{} expects {}, got {:?}", name, expected, first),
+            ))
+        }
+    };
+    let is_string = match first.non_null() {
+        BaseType::Any | BaseType::String => true,
+        _ => false,
+    };
+
+    let ty = match name {
+        "COUNT" => BaseType::BigInt,
+        "ROW_NUMBER" | "RANK" | "DENSE_RANK" | "NTILE" => BaseType::BigInt,
+        "LENGTH" | "CHAR_LENGTH" | "CHARACTER_LENGTH" | "STRPOS" => {
+            expect(is_string, "a string")?;
+            BaseType::Number.nullable_if(nullable)
+        }
+        "SUM" => {
+            expect(is_numeric(&first), "a number")?;
+            // Sums of integers can exceed the integer type
+            let ty = match first.non_null() {
+                BaseType::SmallInt | BaseType::Number | BaseType::BigInt => BaseType::BigInt,
+                ty => ty.clone(),
+            };
+            // The sum of no rows is NULL
+            ty.nullable()
+        }
+        "ABS" | "ROUND" | "CEIL" | "CEILING" | "FLOOR" | "TRUNC" => {
+            expect(is_numeric(&first), "a number")?;
+            first
+        }
+        "AVG" | "STDDEV" | "VARIANCE" => {
+            expect(is_numeric(&first), "a number")?;
+            BaseType::Float.nullable()
+        }
+        "SQRT" | "LN" | "LOG" | "EXP" | "POWER" => {
+            expect(is_numeric(&first), "a number")?;
+            BaseType::Float.nullable_if(nullable)
+        }
+        "MIN" | "MAX" | "ANY_VALUE" | "FIRST_VALUE" | "LAST_VALUE" | "LAG" | "LEAD" => {
+            first.nullable()
+        }
+        "COALESCE" | "IFNULL" => {
+            let ty = unify_all(args)?;
+            // NULL only if every argument can be NULL
+            let nullable = args.iter().all(|ty| ty.is_nullable());
+            ty.non_null().clone().nullable_if(nullable)
+        }
+        "NULLIF" => unify_all(args)?.nullable(),
+        "GREATEST" | "LEAST" => unify_all(args)?,
+        "LOWER" | "UPPER" | "TRIM" | "LTRIM" | "RTRIM" | "INITCAP" | "SUBSTR" | "SUBSTRING"
+        | "REPLACE" | "LPAD" | "RPAD" | "LEFT" | "RIGHT" | "REVERSE" => {
+            expect(is_string, "a string")?;
+            BaseType::String.nullable_if(nullable)
+        }
+        "CONCAT" | "FORMAT" | "TO_CHAR" => BaseType::String.nullable_if(nullable),
+        "STRING_AGG" => BaseType::String.nullable(),
+        "BOOL_AND" | "BOOL_OR" | "LOGICAL_AND" | "LOGICAL_OR" => {
+            let valid = match first.non_null() {
+                BaseType::Any | BaseType::Boolean => true,
+                _ => false,
+            };
+            expect(valid, "a boolean")?;
+            BaseType::Boolean.nullable()
+        }
+        "NOW" | "CURRENT_TIMESTAMP" => BaseType::Timestamp,
+        "CURRENT_DATE" => BaseType::Date,
+        "DATE" => BaseType::Date.nullable_if(nullable),
+        "TIMESTAMP" | "TO_TIMESTAMP" | "DATETIME" => BaseType::Timestamp.nullable_if(nullable),
+        // DATE_TRUNC('month', ts) in PostgreSQL, DATE_TRUNC(date, MONTH) in BigQuery
+        "DATE_TRUNC" | "TIMESTAMP_TRUNC" => {
+            match args
+                .iter()
+                .map(|ty| ty.non_null())
+                .find(|ty| is_temporal(*ty))
+            {
+                Some(BaseType::Date) => BaseType::Date.nullable_if(nullable),
+                _ => BaseType::Timestamp.nullable_if(nullable),
+            }
+        }
+        "DATE_PART" | "DATE_DIFF" | "ARRAY_LENGTH" => BaseType::Number.nullable_if(nullable),
+        "ARRAY_AGG" => BaseType::Array(Box::new(first)).nullable(),
+        "TO_JSON" | "TO_JSONB" | "JSON_BUILD_OBJECT" | "JSONB_BUILD_OBJECT" => BaseType::Json,
+        _ => BaseType::Any,
+    };
+    Ok(ty)
+}
+
 pub fn expr_type(
     expr: &Expr,
-    local_type_env: &HashMap<String, BaseType>,
+    local_type_env: &LocalTypeEnv,
     type_env: im::HashMap<String, TableType>,
     open: bool,
-) -> Result<BaseType, String> {
+) -> Result<BaseType, PowerSqlError> {
     match expr {
         Expr::Value(v) => Ok(value_type(v)),
-        Expr::Identifier(s) => {
-            if open {
-                Ok(*local_type_env
-                    .get(&format!("{}", s))
-                    .unwrap_or(&BaseType::Any))
-            } else {
-                local_type_env
-                    .get(&format!("{}", s))
-                    .copied()
-                    .ok_or_else(|| format!("identifier {} not found", s))
-            }
+        Expr::Identifier(s) => local_type_env.column(&s.value, open),
+        Expr::CompoundIdentifier(idents) => {
+            let idents: Vec<_> = idents.iter().map(|x| x.value.clone()).collect();
+            local_type_env.qualified_column(&idents, open)
         }
         // TODO check if expr can be casted to data type
         Expr::Cast {
             expr: cast_expr,
             data_type,
         } => {
-            let _ = expr_type(cast_expr, local_type_env, type_env, open)?;
+            let ty = expr_type(cast_expr, local_type_env, type_env, open)?;
             // TODO compatible / incompatible casting
-            Ok(map_data_type(&data_type))
+            Ok(map_data_type(&data_type).nullable_if(ty.is_nullable()))
         }
         Expr::Exists(query) => {
-            get_model_type(query, type_env)?;
+            subquery_type(query, Some(local_type_env), type_env)?;
             Ok(BaseType::Boolean)
         }
         Expr::UnaryOp { expr, op } => {
             let ty = expr_type(expr, local_type_env, type_env, open)?;
 
-            match (op, ty) {
+            match (op, ty.non_null()) {
                 (_, BaseType::Any) => Ok(BaseType::Any),
-                (sqlparser::ast::UnaryOperator::Plus, BaseType::Number) => Ok(BaseType::Number),
-                (sqlparser::ast::UnaryOperator::Plus, BaseType::Float) => Ok(BaseType::Float),
-                (sqlparser::ast::UnaryOperator::Minus, BaseType::Number) => Ok(BaseType::Number),
-                (sqlparser::ast::UnaryOperator::Minus, BaseType::Float) => Ok(BaseType::Float),
-                (sqlparser::ast::UnaryOperator::Not, BaseType::Boolean) => Ok(BaseType::Boolean),
-                (a, b) => Err(format!("Could not combine {:?} with {:?}", a, b)),
+                (sqlparser::ast::UnaryOperator::Plus, t) if is_numeric(t) => Ok(ty.clone()),
+                (sqlparser::ast::UnaryOperator::Minus, t) if is_numeric(t) => Ok(ty.clone()),
+                (sqlparser::ast::UnaryOperator::Minus, BaseType::Interval) => Ok(ty.clone()),
+                (sqlparser::ast::UnaryOperator::Not, BaseType::Boolean) => Ok(ty.clone()),
+                (a, b) => Err(PowerSqlError::type_error(
+                    None,
+                    format!("Could not combine {:?} with {:?}", a, b),
+                )),
+            }
+        }
+        Expr::Nested(expr) => expr_type(expr, local_type_env, type_env, open),
+        Expr::IsNull(expr) | Expr::IsNotNull(expr) => {
+            expr_type(expr, local_type_env, type_env, open)?;
+            Ok(BaseType::Boolean)
+        }
+        Expr::BinaryOp { left, op, right } => {
+            let left = expr_type(left, local_type_env, type_env.clone(), open)?;
+            let right = expr_type(right, local_type_env, type_env, open)?;
+            binary_op_type(&left, op, &right)
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            let tys = [
+                expr_type(expr, local_type_env, type_env.clone(), open)?,
+                expr_type(low, local_type_env, type_env.clone(), open)?,
+                expr_type(high, local_type_env, type_env, open)?,
+            ];
+            unify_all(&tys)?;
+            Ok(BaseType::Boolean)
+        }
+        Expr::InList { expr, list, .. } => {
+            let mut tys = vec![expr_type(expr, local_type_env, type_env.clone(), open)?];
+            for e in list {
+                tys.push(expr_type(e, local_type_env, type_env.clone(), open)?);
+            }
+            unify_all(&tys)?;
+            Ok(BaseType::Boolean)
+        }
+        Expr::InSubquery { expr, subquery, .. } => {
+            expr_type(expr, local_type_env, type_env.clone(), open)?;
+            subquery_type(subquery, Some(local_type_env), type_env)?;
+            Ok(BaseType::Boolean)
+        }
+        Expr::Subquery(query) => match subquery_type(query, Some(local_type_env), type_env)? {
+            // A scalar subquery has the type of its only column
+            TableType::Closed(columns) if columns.len() == 1 => {
+                // NULL when the subquery has no rows
+                Ok(columns.values().next().unwrap().clone().nullable())
+            }
+            _ => Ok(BaseType::Any),
+        },
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            let operand = match operand {
+                Some(operand) => Some(expr_type(operand, local_type_env, type_env.clone(), open)?),
+                None => None,
+            };
+            for condition in conditions {
+                let ty = expr_type(condition, local_type_env, type_env.clone(), open)?;
+                match &operand {
+                    // CASE x WHEN value THEN ...
+                    Some(operand) => {
+                        unify(operand, &ty)?;
+                    }
+                    // CASE WHEN condition THEN ...
+                    None => {
+                        if *ty.non_null() != BaseType::Any && *ty.non_null() != BaseType::Boolean {
+                            return Err(PowerSqlError::type_error(
+                                None,
+                                format!("Expected boolean in CASE WHEN, got {:?}", ty),
+                            ));
+                        }
+                    }
+                }
             }
+            let mut tys = vec![];
+            for result in results.iter().chain(else_result.iter().map(|x| x.as_ref())) {
+                tys.push(expr_type(result, local_type_env, type_env.clone(), open)?);
+            }
+            // Without ELSE the result is NULL when no condition matches
+            Ok(unify_all(&tys)?.nullable_if(else_result.is_none()))
+        }
+        Expr::Function(function) => {
+            let mut args = vec![];
+            for arg in function.args.iter() {
+                args.push(expr_type(arg, local_type_env, type_env.clone(), open)?);
+            }
+            function_type(&function.name.to_string().to_uppercase(), &args)
         }
+        Expr::Extract { expr, .. } => {
+            let ty = expr_type(expr, local_type_env, type_env, open)?;
+            Ok(BaseType::Number.nullable_if(ty.is_nullable()))
+        }
+        Expr::TypedString { data_type, .. } => Ok(map_data_type(data_type)),
+        Expr::Collate { expr, .. } => expr_type(expr, local_type_env, type_env, open),
         // TODO extend
         _ => Ok(BaseType::Any),
     }
 }
 
-fn build_local_type_env(
-    mut type_env: im::HashMap<String, TableType>,
-    local_type_env: &mut HashMap<String, BaseType>,
-    table_factor: &TableFactor,
-) -> Result<(bool, im::HashMap<String, TableType>), String> {
-    let mut unknown_sources = false;
+impl TableType {
+    pub fn columns(&self) -> &HashMap<String, BaseType> {
+        match self {
+            TableType::Open(columns) | TableType::Closed(columns) => columns,
+        }
+    }
 
-    match table_factor {
-        TableFactor::Table { name, .. } => {
-            // TODO use alias to register name in environment
-            if let Some(ty) = type_env.get(&name.to_string()) {
-                match ty {
-                    TableType::Open(s) => {
-                        for (s, ty) in s {
-                            local_type_env.insert(s.clone(), *ty);
-                        }
-                    }
-                    TableType::Closed(s) => {
-                        for (s, ty) in s {
-                            local_type_env.insert(s.clone(), *ty);
+    fn is_open(&self) -> bool {
+        match self {
+            TableType::Open(_) => true,
+            TableType::Closed(_) => false,
+        }
+    }
+
+    /// The same table where every column can be NULL
+    fn nullable(self) -> TableType {
+        let nullable = |columns: HashMap<String, BaseType>| {
+            columns
+                .into_iter()
+                .map(|(name, ty)| (name, ty.nullable()))
+                .collect()
+        };
+        match self {
+            TableType::Open(columns) => TableType::Open(nullable(columns)),
+            TableType::Closed(columns) => TableType::Closed(nullable(columns)),
+        }
+    }
+}
+
+/// The tables in the FROM clause of a query, by alias or name
+#[derive(Debug, Default, Clone)]
+pub struct LocalTypeEnv {
+    tables: Vec<(String, TableType)>,
+    // Columns joined with USING or NATURAL, which are not ambiguous
+    using: Vec<String>,
+    // The tables of the enclosing query, for correlated subqueries
+    parent: Option<Box<LocalTypeEnv>>,
+}
+
+impl LocalTypeEnv {
+    /// An empty scope in a subquery of `parent`
+    fn scope(parent: Option<&LocalTypeEnv>) -> LocalTypeEnv {
+        LocalTypeEnv {
+            parent: parent.cloned().map(Box::new),
+            ..LocalTypeEnv::default()
+        }
+    }
+
+    fn add_table(&mut self, name: String, ty: TableType) -> Result<(), PowerSqlError> {
+        if self.tables.iter().any(|(n, _)| *n == name) {
+            return Err(PowerSqlError::type_error(
+                None,
+                format!("Table name {} specified more than once, use an alias", name),
+            ));
+        }
+        self.tables.push((name, ty));
+        Ok(())
+    }
+
+    /// Adds the tables of a joined relation, outer joins make the columns
+    /// of the side without a matching row nullable
+    fn join(
+        &mut self,
+        other: LocalTypeEnv,
+        join_operator: &JoinOperator,
+    ) -> Result<(), PowerSqlError> {
+        let (left_nullable, right_nullable) = match join_operator {
+            JoinOperator::LeftOuter(_) => (false, true),
+            JoinOperator::RightOuter(_) => (true, false),
+            JoinOperator::FullOuter(_) => (true, true),
+            _ => (false, false),
+        };
+        match join_constraint(join_operator) {
+            Some(JoinConstraint::Using(columns)) => {
+                self.using.extend(columns.iter().map(|x| x.value.clone()))
+            }
+            Some(JoinConstraint::Natural) => {
+                for (_, ty) in other.tables.iter() {
+                    for column in ty.columns().keys() {
+                        if self
+                            .tables
+                            .iter()
+                            .any(|(_, t)| t.columns().contains_key(column))
+                        {
+                            self.using.push(column.clone());
                         }
                     }
                 }
-            } else {
-                unknown_sources = true;
             }
+            _ => {}
+        }
+        self.using.extend(other.using);
+        if left_nullable {
+            self.tables = self
+                .tables
+                .drain(..)
+                .map(|(name, ty)| (name, ty.nullable()))
+                .collect();
+        }
+        for (name, ty) in other.tables {
+            let ty = if right_nullable { ty.nullable() } else { ty };
+            self.add_table(name, ty)?;
+        }
+        Ok(())
+    }
+
+    fn table(&self, name: &str) -> Option<&TableType> {
+        self.tables
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, ty)| ty)
+    }
+
+    fn is_open(&self) -> bool {
+        self.tables.iter().any(|(_, ty)| ty.is_open())
+    }
+
+    /// Type of a column without table name
+    fn column(&self, name: &str, open: bool) -> Result<BaseType, PowerSqlError> {
+        let found: Vec<_> = self
+            .tables
+            .iter()
+            .filter_map(|(_, ty)| ty.columns().get(name))
+            .collect();
+        match found.as_slice() {
+            [ty] => Ok((*ty).clone()),
+            [ty, ..] if self.using.iter().any(|x| x == name) => Ok((*ty).clone()),
+            // Might be a column of a table with unknown columns
+            [] if open || self.is_open() => Ok(BaseType::Any),
+            [] if self.parent.is_some() => self.parent.as_ref().unwrap().column(name, open),
+            [] => Err(PowerSqlError::type_error(
+                Some(name),
+                format!("identifier {} not found", name),
+            )),
+            _ => Err(PowerSqlError::type_error(
+                Some(name),
+                format!("column reference {} is ambiguous", name),
+            )),
+        }
+    }
+
+    /// Type of `table.column`, `schema.table.column` or `column.field`
+    fn qualified_column(&self, idents: &[String], open: bool) -> Result<BaseType, PowerSqlError> {
+        let name = idents.join(".");
+        let (ty, fields) = match idents {
+            [table, column, fields @ ..] if self.table(table).is_some() => (
+                table_column(self.table(table).unwrap(), column, &name)?,
+                fields,
+            ),
+            [_schema, table, column, fields @ ..] if self.table(table).is_some() => (
+                table_column(self.table(table).unwrap(), column, &name)?,
+                fields,
+            ),
+            [column, fields @ ..]
+                if self
+                    .tables
+                    .iter()
+                    .any(|(_, ty)| ty.columns().contains_key(column)) =>
+            {
+                (self.column(column, open)?, fields)
+            }
+            // Refers to a table of the enclosing query
+            _ if self.parent.is_some() => {
+                return self.parent.as_ref().unwrap().qualified_column(idents, open)
+            }
+            _ if open || self.is_open() => return Ok(BaseType::Any),
+            _ => {
+                return Err(PowerSqlError::type_error(
+                    Some(name.as_str()),
+                    format!("missing FROM-clause entry for {}", idents[0]),
+                ))
+            }
+        };
+        fields
+            .iter()
+            .try_fold(ty, |ty, field| field_type(&ty, field, &name))
+    }
+}
+
+fn join_constraint(join_operator: &JoinOperator) -> Option<&JoinConstraint> {
+    match join_operator {
+        JoinOperator::Inner(constraint)
+        | JoinOperator::LeftOuter(constraint)
+        | JoinOperator::RightOuter(constraint)
+        | JoinOperator::FullOuter(constraint) => Some(constraint),
+        _ => None,
+    }
+}
+
+/// Checks that a `WHERE`, `HAVING` or `ON` condition is a boolean
+fn expect_boolean(
+    expr: &Expr,
+    local_type_env: &LocalTypeEnv,
+    type_env: im::HashMap<String, TableType>,
+    clause: &str,
+) -> Result<(), PowerSqlError> {
+    let ty = expr_type(expr, local_type_env, type_env, false)?;
+    match ty.non_null() {
+        BaseType::Any | BaseType::Boolean => Ok(()),
+        ty => Err(PowerSqlError::type_error(
+            None,
+            format!("Expected boolean in {}, got {:?}", clause, ty),
+        )),
+    }
+}
+
+/// Adds a joined relation and checks the join condition
+fn add_join(
+    type_env: &im::HashMap<String, TableType>,
+    local_type_env: &mut LocalTypeEnv,
+    join: &Join,
+) -> Result<(), PowerSqlError> {
+    let mut join_type_env = LocalTypeEnv::default();
+    build_local_type_env(type_env, &mut join_type_env, &join.relation)?;
+    local_type_env.join(join_type_env, &join.join_operator)?;
+    if let Some(JoinConstraint::On(expr)) = join_constraint(&join.join_operator) {
+        expect_boolean(expr, local_type_env, type_env.clone(), "JOIN ON")?;
+    }
+    Ok(())
+}
+
+fn is_aggregate(function: &Function) -> bool {
+    // Window functions are computed per row
+    if function.over.is_some() {
+        return false;
+    }
+    match function.name.to_string().to_uppercase().as_str() {
+        "COUNT" | "SUM" | "AVG" | "MIN" | "MAX" | "ANY_VALUE" | "ARRAY_AGG" | "STRING_AGG"
+        | "BOOL_AND" | "BOOL_OR" | "LOGICAL_AND" | "LOGICAL_OR" | "STDDEV" | "VARIANCE" => true,
+        _ => false,
+    }
+}
+
+fn contains_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::Function(function) => {
+            is_aggregate(function) || function.args.iter().any(contains_aggregate)
+        }
+        Expr::BinaryOp { left, right, .. } => contains_aggregate(left) || contains_aggregate(right),
+        Expr::UnaryOp { expr, .. }
+        | Expr::Nested(expr)
+        | Expr::Cast { expr, .. }
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr) => contains_aggregate(expr),
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            operand
+                .iter()
+                .chain(else_result.iter())
+                .any(|x| contains_aggregate(x))
+                || conditions
+                    .iter()
+                    .chain(results.iter())
+                    .any(contains_aggregate)
+        }
+        _ => false,
+    }
+}
+
+/// Whether the expressions refer to the same column, `t.a` is the same as `a`
+/// but not as `u.a`
+fn same_expr(a: &Expr, b: &Expr) -> bool {
+    let column = |expr: &Expr| match expr {
+        Expr::Identifier(id) => Some(vec![id.value.clone()]),
+        Expr::CompoundIdentifier(ids) => Some(ids.iter().map(|x| x.value.clone()).collect()),
+        _ => None,
+    };
+    match (column(a), column(b)) {
+        (Some(a), Some(b)) if a.len() == 1 || b.len() == 1 => a.last() == b.last(),
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Checks that columns outside aggregates are in the `GROUP BY` clause
+fn check_grouped(expr: &Expr, group_by: &[Expr]) -> Result<(), PowerSqlError> {
+    if group_by.iter().any(|x| same_expr(x, expr)) {
+        return Ok(());
+    }
+    match expr {
+        Expr::Identifier(_) | Expr::CompoundIdentifier(_) => Err(PowerSqlError::type_error(
+            Some(expr.to_string().as_str()),
+            format!(
+                "column {} must appear in the GROUP BY clause or be used in an aggregate function",
+                expr
+            ),
+        )),
+        Expr::Function(function) if is_aggregate(function) || function.over.is_some() => Ok(()),
+        Expr::Function(function) => function
+            .args
+            .iter()
+            .try_for_each(|x| check_grouped(x, group_by)),
+        Expr::BinaryOp { left, right, .. } => {
+            check_grouped(left, group_by)?;
+            check_grouped(right, group_by)
+        }
+        Expr::UnaryOp { expr, .. }
+        | Expr::Nested(expr)
+        | Expr::Cast { expr, .. }
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr)
+        | Expr::Extract { expr, .. }
+        | Expr::Collate { expr, .. } => check_grouped(expr, group_by),
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            check_grouped(expr, group_by)?;
+            check_grouped(low, group_by)?;
+            check_grouped(high, group_by)
+        }
+        Expr::InList { expr, list, .. } => {
+            check_grouped(expr, group_by)?;
+            list.iter().try_for_each(|x| check_grouped(x, group_by))
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => operand
+            .iter()
+            .chain(else_result.iter())
+            .map(|x| x.as_ref())
+            .chain(conditions.iter())
+            .chain(results.iter())
+            .try_for_each(|x| check_grouped(x, group_by)),
+        _ => Ok(()),
+    }
+}
+
+/// The projected expression for `GROUP BY` and `ORDER BY` positions and aliases
+fn projection_expr<'a>(expr: &Expr, projection: &'a [SelectItem]) -> Option<&'a Expr> {
+    match expr {
+        Expr::Value(Value::Number(n)) => {
+            let position = n.parse::<usize>().ok()?;
+            match projection.get(position.checked_sub(1)?)? {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                    Some(expr)
+                }
+                _ => None,
+            }
+        }
+        Expr::Identifier(id) => projection.iter().find_map(|p| match p {
+            SelectItem::ExprWithAlias { expr, alias } if alias.value == id.value => Some(expr),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+fn table_column(table: &TableType, column: &str, name: &str) -> Result<BaseType, PowerSqlError> {
+    match (table.columns().get(column), table) {
+        (Some(ty), _) => Ok(ty.clone()),
+        (None, TableType::Open(_)) => Ok(BaseType::Any),
+        (None, TableType::Closed(_)) => Err(PowerSqlError::type_error(
+            Some(name),
+            format!("identifier {} not found", name),
+        )),
+    }
+}
+
+/// Type of a field of a struct, NULL when the struct is NULL
+fn field_type(ty: &BaseType, field: &str, name: &str) -> Result<BaseType, PowerSqlError> {
+    match ty.non_null() {
+        BaseType::Any => Ok(BaseType::Any),
+        // Fields are unknown
+        BaseType::Struct(fields) if fields.is_empty() => Ok(BaseType::Any),
+        BaseType::Struct(fields) => fields
+            .iter()
+            .find(|(n, _)| n == field)
+            .map(|(_, field_ty)| field_ty.clone().nullable_if(ty.is_nullable()))
+            .ok_or_else(|| {
+                PowerSqlError::type_error(Some(name), format!("field {} not found", field))
+            }),
+        ty => Err(PowerSqlError::type_error(
+            Some(name),
+            format!("Expected struct for field {}, got {:?}", field, ty),
+        )),
+    }
+}
+
+fn build_local_type_env(
+    type_env: &im::HashMap<String, TableType>,
+    local_type_env: &mut LocalTypeEnv,
+    table_factor: &TableFactor,
+) -> Result<(), PowerSqlError> {
+    match table_factor {
+        TableFactor::Table { name, alias, .. } => {
+            // Columns can be qualified with the alias or the table name without schema
+            let local_name = match alias {
+                Some(alias) => alias.name.value.clone(),
+                None => name.0.last().map(|x| x.value.clone()).unwrap_or_default(),
+            };
+            // Tables that are not known can have any column
+            let ty = type_env
+                .get(&name.to_string())
+                .or_else(|| type_env.get(&unquoted_name(name)))
+                .cloned()
+                .unwrap_or_else(|| TableType::Open(HashMap::new()));
+            local_type_env.add_table(local_name, ty)?;
         }
         TableFactor::NestedJoin(join) => {
-            let (unknow_sources_2, n_type_env) =
-                build_local_type_env(type_env.clone(), local_type_env, &join.relation)?;
-            type_env = n_type_env;
-            unknown_sources = unknown_sources || unknow_sources_2;
+            build_local_type_env(type_env, local_type_env, &join.relation)?;
 
             for j in join.joins.iter() {
-                let (unknow_sources_2, n_type_env) =
-                    build_local_type_env(type_env.clone(), local_type_env, &j.relation)?;
-                type_env = n_type_env;
-                unknown_sources = unknown_sources || unknow_sources_2;
+                add_join(type_env, local_type_env, j)?;
             }
         }
         TableFactor::Derived {
@@ -141,85 +948,354 @@ fn build_local_type_env(
             ..
         } => {
             let ty = get_model_type(subquery, type_env.clone())?;
-            type_env = type_env.update(format!("{}", alias.name), ty);
+            local_type_env.add_table(alias.name.value.clone(), ty)?;
+        }
+        TableFactor::Derived { .. } => {
+            return Err(PowerSqlError::type_error(
+                None,
+                "Derived tables should have alias",
+            ))
         }
-        TableFactor::Derived { .. } => return Err("Derived tables should have alias".to_string()),
     }
-    Ok((unknown_sources, type_env))
+    Ok(())
 }
 
-pub fn get_model_type(
-    query: &Query,
-    mut type_env: im::HashMap<String, TableType>,
-) -> Result<TableType, String> {
-    for cte in query.ctes.iter() {
-        let ty = get_model_type(&cte.query, type_env.clone())?;
-        type_env = type_env.update(format!("{}", cte.alias), ty);
+/// Columns of a query in order, unnamed expressions have no name
+struct QueryColumns {
+    columns: Vec<(Option<String>, BaseType)>,
+    // Selects all columns of a table with unknown columns
+    open: bool,
+    // False when the order of the columns is not known, e.g. for SELECT *
+    positional: bool,
+}
+
+fn select_type(
+    select: &Select,
+    order_by: &[OrderByExpr],
+    parent: Option<&LocalTypeEnv>,
+    type_env: &im::HashMap<String, TableType>,
+) -> Result<QueryColumns, PowerSqlError> {
+    let mut is_open = false;
+    let mut positional = true;
+
+    let mut local_type_env = LocalTypeEnv::scope(parent);
+
+    for table in select.from.iter() {
+        let mut table_type_env = LocalTypeEnv::scope(parent);
+        build_local_type_env(type_env, &mut table_type_env, &table.relation)?;
+        for join in table.joins.iter() {
+            add_join(type_env, &mut table_type_env, join)?;
+        }
+        local_type_env.join(table_type_env, &JoinOperator::CrossJoin)?;
+    }
+    if let Some(selection) = &select.selection {
+        expect_boolean(selection, &local_type_env, type_env.clone(), "WHERE")?;
+    }
+    for expr in select.group_by.iter() {
+        // GROUP BY 1 and GROUP BY alias refer to the projection
+        if projection_expr(expr, &select.projection).is_none() {
+            expr_type(expr, &local_type_env, type_env.clone(), false)?;
+        }
+    }
+    if let Some(having) = &select.having {
+        expect_boolean(having, &local_type_env, type_env.clone(), "HAVING")?;
     }
-    match &query.body {
-        sqlparser::ast::SetExpr::Select(select) => {
-            let mut is_open = false;
-            let mut unknown_sources = false;
-
-            let mut local_type_env = HashMap::new();
-            // TODO optimize / simplify
-            // (Re-)use immutable hashmap?
-
-            for table in select.from.iter() {
-                for join in table.joins.iter() {
-                    let (unknow_sources_2, n_type_env) =
-                        build_local_type_env(type_env, &mut local_type_env, &join.relation)?;
-                    type_env = n_type_env;
-                    unknown_sources = unknown_sources || unknow_sources_2;
+    let mut items = vec![];
+
+    for p in &select.projection {
+        match p {
+            SelectItem::ExprWithAlias { expr, alias } => {
+                let ty = expr_type(expr, &local_type_env, type_env.clone(), false)?;
+                items.push((Some(alias.to_string()), ty));
+            }
+            SelectItem::UnnamedExpr(expr) => {
+                let ty = expr_type(expr, &local_type_env, type_env.clone(), false)?;
+                let name = match expr {
+                    Expr::Identifier(id) => Some(id.to_string()),
+                    Expr::CompoundIdentifier(ids) => Some(ids.last().unwrap().to_string()),
+                    _ => None,
+                };
+                items.push((name, ty));
+            }
+            SelectItem::Wildcard => {
+                // The order of the columns of tables is not known
+                positional = false;
+                for (_, ty) in local_type_env.tables.iter() {
+                    is_open = is_open || ty.is_open();
+                    items.extend(
+                        ty.columns()
+                            .iter()
+                            .map(|(x, ty)| (Some(x.clone()), ty.clone())),
+                    );
                 }
-                let (unknow_sources_2, n_type_env) =
-                    build_local_type_env(type_env, &mut local_type_env, &table.relation)?;
-                type_env = n_type_env;
-                unknown_sources = unknown_sources || unknow_sources_2;
+                // SELECT * without FROM
+                is_open = is_open || local_type_env.tables.is_empty();
             }
-            let mut items = vec![];
-
-            for p in &select.projection {
-                match p {
-                    SelectItem::ExprWithAlias { expr, alias } => {
-                        let ty =
-                            expr_type(&expr, &local_type_env, type_env.clone(), unknown_sources)?;
-                        items.push((alias.to_string(), ty));
+            SelectItem::QualifiedWildcard(name) => {
+                let table_name = name.0.last().map(|x| x.value.as_str()).unwrap_or_default();
+                match local_type_env.table(table_name) {
+                    Some(ty) => {
+                        positional = false;
+                        is_open = is_open || ty.is_open();
+                        items.extend(
+                            ty.columns()
+                                .iter()
+                                .map(|(x, ty)| (Some(x.clone()), ty.clone())),
+                        );
                     }
-                    SelectItem::UnnamedExpr(expr) => match expr {
-                        Expr::Identifier(id) => {
-                            let ty = expr_type(
-                                &expr,
-                                &local_type_env,
-                                type_env.clone(),
-                                unknown_sources,
-                            )?;
-                            items.push((id.to_string(), ty))
-                        }
-                        _ => {}
-                    },
-                    _ => {
-                        is_open = true;
+                    None => {
+                        return Err(PowerSqlError::type_error(
+                            None,
+                            format!("missing FROM-clause entry for {}", name),
+                        ))
                     }
                 }
             }
+        }
+    }
 
-            let map = items
+    let group_by: Vec<Expr> = select
+        .group_by
+        .iter()
+        .map(|expr| projection_expr(expr, &select.projection).unwrap_or(expr))
+        .cloned()
+        .collect();
+    let projected = select.projection.iter().filter_map(|p| match p {
+        SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => Some(expr),
+        _ => None,
+    });
+    let aggregated = projected
+        .clone()
+        .chain(select.having.iter())
+        .any(contains_aggregate);
+    if !group_by.is_empty() || aggregated {
+        for expr in projected.chain(select.having.iter()) {
+            check_grouped(expr, &group_by)?;
+        }
+    }
+
+    for order_by in order_by.iter() {
+        match &order_by.expr {
+            Expr::Value(Value::Number(n)) => {
+                if projection_expr(&order_by.expr, &select.projection).is_none() {
+                    return Err(PowerSqlError::type_error(
+                        None,
+                        format!("ORDER BY position {} is not in select list", n),
+                    ));
+                }
+            }
+            // Columns of the result
+            Expr::Identifier(id)
+                if items
+                    .iter()
+                    .any(|(x, _)| x.as_deref() == Some(id.to_string().as_str())) => {}
+            expr => {
+                expr_type(expr, &local_type_env, type_env.clone(), false)?;
+            }
+        }
+    }
+
+    Ok(QueryColumns {
+        columns: items,
+        open: is_open,
+        positional,
+    })
+}
+
+/// Checks that `ORDER BY` of a set operation refers to columns of the result
+fn check_result_order_by(
+    columns: &QueryColumns,
+    order_by: &[OrderByExpr],
+) -> Result<(), PowerSqlError> {
+    for order_by in order_by {
+        let valid = match &order_by.expr {
+            Expr::Value(Value::Number(n)) => n
+                .parse::<usize>()
+                .map_or(false, |n| n >= 1 && n <= columns.columns.len()),
+            Expr::Identifier(id) => {
+                columns.open
+                    || columns
+                        .columns
+                        .iter()
+                        .any(|(x, _)| x.as_deref() == Some(id.value.as_str()))
+            }
+            _ => columns.open,
+        };
+        if !valid {
+            return Err(PowerSqlError::type_error(
+                None,
+                format!("ORDER BY {} is not a column of the result", order_by.expr),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Result of `UNION`, `INTERSECT` or `EXCEPT`, with the column names of the left query
+fn set_operation_type(
+    op: &SetOperator,
+    left: QueryColumns,
+    right: QueryColumns,
+) -> Result<QueryColumns, PowerSqlError> {
+    if left.open || right.open {
+        return Ok(QueryColumns {
+            columns: left.columns,
+            open: true,
+            positional: false,
+        });
+    }
+    if left.columns.len() != right.columns.len() {
+        return Err(PowerSqlError::type_error(
+            None,
+            format!(
+                "Each {} query must have the same number of columns, got {} and {}",
+                op,
+                left.columns.len(),
+                right.columns.len()
+            ),
+        ));
+    }
+    let positional = left.positional && right.positional;
+    let mut columns = vec![];
+    for (i, (name, ty)) in left.columns.iter().enumerate() {
+        // Without known order, columns are matched by name
+        let other = if positional {
+            Some(&right.columns[i].1)
+        } else {
+            right
+                .columns
                 .iter()
-                .filter(|(x, _)| x != "*")
-                .map(|(x, ty)| (x.to_string(), *ty))
-                .collect();
-            if is_open {
-                Ok(TableType::Open(map))
-            } else {
-                Ok(TableType::Closed(map))
+                .find(|(x, _)| x.is_some() && x == name)
+                .map(|(_, ty)| ty)
+        };
+        let ty = match other {
+            Some(other) => unify(ty, other).map_err(|_| {
+                PowerSqlError::type_error(
+                    name.as_deref(),
+                    format!("{} column {} has types {:?} and {:?}", op, i + 1, ty, other),
+                )
+            })?,
+            None => ty.clone(),
+        };
+        columns.push((name.clone(), ty));
+    }
+    Ok(QueryColumns {
+        columns,
+        open: false,
+        positional,
+    })
+}
+
+/// Type of `VALUES` rows, the columns are named column1, column2, ...
+fn values_type(values: &Values) -> Result<QueryColumns, PowerSqlError> {
+    let mut columns: Vec<(Option<String>, BaseType)> = vec![];
+    for row in values.0.iter() {
+        if !columns.is_empty() && row.len() != columns.len() {
+            return Err(PowerSqlError::type_error(
+                None,
+                "VALUES lists must all be the same length",
+            ));
+        }
+        for (i, expr) in row.iter().enumerate() {
+            let ty = expr_type(expr, &LocalTypeEnv::default(), im::HashMap::new(), false)?;
+            match columns.get_mut(i) {
+                Some((_, column)) => *column = unify(column, &ty)?,
+                None => columns.push((Some(format!("column{}", i + 1)), ty)),
             }
         }
-        sqlparser::ast::SetExpr::Query(query) => get_model_type(query, type_env),
-        _ => Err("Statement not yet implemented".to_string()),
+    }
+    Ok(QueryColumns {
+        columns,
+        open: false,
+        positional: true,
+    })
+}
+
+fn set_expr_type(
+    body: &SetExpr,
+    order_by: &[OrderByExpr],
+    parent: Option<&LocalTypeEnv>,
+    type_env: &im::HashMap<String, TableType>,
+) -> Result<QueryColumns, PowerSqlError> {
+    match body {
+        SetExpr::Select(select) => select_type(select, order_by, parent, type_env),
+        SetExpr::Query(query) => {
+            let columns = query_type(query, parent, type_env.clone())?;
+            check_result_order_by(&columns, order_by)?;
+            Ok(columns)
+        }
+        SetExpr::SetOperation {
+            op, left, right, ..
+        } => {
+            let left = set_expr_type(left, &[], parent, type_env)?;
+            let right = set_expr_type(right, &[], parent, type_env)?;
+            let columns = set_operation_type(op, left, right)?;
+            check_result_order_by(&columns, order_by)?;
+            Ok(columns)
+        }
+        SetExpr::Values(values) => {
+            let columns = values_type(values)?;
+            check_result_order_by(&columns, order_by)?;
+            Ok(columns)
+        }
     }
 }
 
+fn query_type(
+    query: &Query,
+    parent: Option<&LocalTypeEnv>,
+    mut type_env: im::HashMap<String, TableType>,
+) -> Result<QueryColumns, PowerSqlError> {
+    for cte in query.ctes.iter() {
+        let ty = subquery_type(&cte.query, parent, type_env.clone())?;
+        type_env = type_env.update(format!("{}", cte.alias), ty);
+    }
+    set_expr_type(&query.body, &query.order_by, parent, &type_env)
+}
+
+pub fn get_model_type(
+    query: &Query,
+    type_env: im::HashMap<String, TableType>,
+) -> Result<TableType, PowerSqlError> {
+    subquery_type(query, None, type_env)
+}
+
+/// Type of a query that can refer to the tables of the enclosing query in `parent`
+fn subquery_type(
+    query: &Query,
+    parent: Option<&LocalTypeEnv>,
+    type_env: im::HashMap<String, TableType>,
+) -> Result<TableType, PowerSqlError> {
+    let QueryColumns { columns, open, .. } = query_type(query, parent, type_env)?;
+
+    let map = columns
+        .into_iter()
+        .filter_map(|(x, ty)| x.map(|x| (x, ty)))
+        .filter(|(x, _)| x != "*")
+        .collect();
+    if open {
+        Ok(TableType::Open(map))
+    } else {
+        Ok(TableType::Closed(map))
+    }
+}
+
+/// Tables read by the query that are neither in `known` nor a CTE in scope
+pub fn unknown_tables(query: &Query, known: &im::HashSet<String>) -> Vec<String> {
+    let mut query = query.clone();
+    let mut res: Vec<String> = vec![];
+    visit_tables_query(&mut query, &im::HashSet::new(), &mut |name| {
+        let quoted = name.to_string();
+        if !known.contains(&quoted)
+            && !known.contains(&unquoted_name(name))
+            && !res.contains(&quoted)
+        {
+            res.push(quoted);
+        }
+    });
+    res
+}
+
 #[cfg(test)]
 use super::parser::PowerSqlDialect;
 #[cfg(test)]
@@ -352,3 +1428,385 @@ pub fn get_cast_type() {
         ))
     )
 }
+
+#[test]
+pub fn get_expression_types() {
+    let sql = "SELECT x + 1 AS a, x / 2.5 AS b, x > 1 AND y LIKE 'a%' AS c, \
+               CASE WHEN x > 1 THEN 1 ELSE 2.0 END AS d, COALESCE(y, 'none') AS e, \
+               SUM(x) AS f, COUNT(*) AS g, LOWER(y) || '!' AS h FROM t";
+    let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+        .tokenize()
+        .unwrap();
+    let mut parser = Parser::new(tokens);
+    let query = parser.parse_query().unwrap();
+    let ty = get_model_type(
+        &query,
+        im::HashMap::from(hashmap! {"t".to_string() =>
+            TableType::Closed(hashmap! {
+                "x".to_string() => BaseType::Number,
+                "y".to_string() => BaseType::String,
+            })
+        }),
+    );
+
+    assert_eq!(
+        ty,
+        Ok(TableType::Closed(hashmap! {
+            "a".to_string() => BaseType::Number,
+            "b".to_string() => BaseType::Float,
+            "c".to_string() => BaseType::Boolean,
+            "d".to_string() => BaseType::Float,
+            "e".to_string() => BaseType::String,
+            "f".to_string() => BaseType::BigInt.nullable(),
+            "g".to_string() => BaseType::BigInt,
+            "h".to_string() => BaseType::String,
+        }))
+    )
+}
+
+#[test]
+pub fn get_expression_type_errors() {
+    let type_env = im::HashMap::from(hashmap! {"t".to_string() =>
+        TableType::Closed(hashmap! {
+            "x".to_string() => BaseType::Number,
+            "y".to_string() => BaseType::String,
+        })
+    });
+    for sql in &[
+        "SELECT x + y AS a FROM t",
+        "SELECT CASE WHEN x > 1 THEN y ELSE x END AS a FROM t",
+        "SELECT SUM(y) AS a FROM t",
+        "SELECT x AND TRUE AS a FROM t",
+        "SELECT x IN (1, 'a') AS a FROM t",
+    ] {
+        let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+            .tokenize()
+            .unwrap();
+        let mut parser = Parser::new(tokens);
+        let query = parser.parse_query().unwrap();
+
+        assert!(get_model_type(&query, type_env.clone()).is_err(), "{}", sql);
+    }
+}
+
+#[test]
+pub fn get_nullable_types() {
+    let sql = "SELECT order_id AS id, name, COALESCE(name, 'unknown') AS label, \
+               created_at + INTERVAL '1 day' AS due, CAST(amount AS DECIMAL(10, 2)) AS amount \
+               FROM o LEFT JOIN c ON o.customer_id = c.id";
+    let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+        .tokenize()
+        .unwrap();
+    let mut parser = Parser::new(tokens);
+    let query = parser.parse_query().unwrap();
+    let ty = get_model_type(
+        &query,
+        im::HashMap::from(hashmap! {
+            "o".to_string() => TableType::Closed(hashmap! {
+                "order_id".to_string() => BaseType::BigInt,
+                "customer_id".to_string() => BaseType::BigInt,
+                "created_at".to_string() => BaseType::Date,
+                "amount".to_string() => BaseType::Float,
+            }),
+            "c".to_string() => TableType::Closed(hashmap! {
+                "id".to_string() => BaseType::BigInt,
+                "name".to_string() => BaseType::String,
+            }),
+        }),
+    );
+
+    assert_eq!(
+        ty,
+        Ok(TableType::Closed(hashmap! {
+            "id".to_string() => BaseType::BigInt,
+            "name".to_string() => BaseType::String.nullable(),
+            "label".to_string() => BaseType::String,
+            "due".to_string() => BaseType::Timestamp,
+            "amount".to_string() => BaseType::Decimal { precision: Some(10), scale: Some(2) },
+        }))
+    )
+}
+
+#[test]
+pub fn get_qualified_column_types() {
+    let sql = "SELECT r.id, p.id AS product_id, p.name, r.* FROM reviews r JOIN products AS p \
+               ON r.product_id = p.id";
+    let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+        .tokenize()
+        .unwrap();
+    let mut parser = Parser::new(tokens);
+    let query = parser.parse_query().unwrap();
+    let ty = get_model_type(
+        &query,
+        im::HashMap::from(hashmap! {
+            "reviews".to_string() => TableType::Closed(hashmap! {
+                "id".to_string() => BaseType::BigInt,
+                "score".to_string() => BaseType::SmallInt,
+            }),
+            "products".to_string() => TableType::Closed(hashmap! {
+                "id".to_string() => BaseType::Number,
+                "name".to_string() => BaseType::String,
+            }),
+        }),
+    );
+
+    assert_eq!(
+        ty,
+        Ok(TableType::Closed(hashmap! {
+            "id".to_string() => BaseType::BigInt,
+            "product_id".to_string() => BaseType::Number,
+            "name".to_string() => BaseType::String,
+            "score".to_string() => BaseType::SmallInt,
+        }))
+    )
+}
+
+#[test]
+pub fn get_column_resolution_errors() {
+    let type_env = im::HashMap::from(hashmap! {
+        "a".to_string() => TableType::Closed(hashmap! {"id".to_string() => BaseType::Number}),
+        "b".to_string() => TableType::Closed(hashmap! {"id".to_string() => BaseType::Number}),
+    });
+    for sql in &[
+        // Ambiguous column
+        "SELECT id FROM a JOIN b ON a.id = b.id",
+        // Missing column in a known table
+        "SELECT a.name FROM a",
+        // Table not in the FROM clause
+        "SELECT c.id FROM a",
+        // The table name is replaced by the alias
+        "SELECT a.id FROM a AS x",
+        "SELECT a.id FROM a JOIN a ON 1 = 1",
+    ] {
+        let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+            .tokenize()
+            .unwrap();
+        let mut parser = Parser::new(tokens);
+        let query = parser.parse_query().unwrap();
+
+        assert!(get_model_type(&query, type_env.clone()).is_err(), "{}", sql);
+    }
+}
+
+#[test]
+pub fn check_clauses() {
+    let type_env = im::HashMap::from(hashmap! {
+        "a".to_string() => TableType::Closed(hashmap! {
+            "id".to_string() => BaseType::Number,
+            "category".to_string() => BaseType::String,
+        }),
+        "b".to_string() => TableType::Closed(hashmap! {
+            "id".to_string() => BaseType::Number,
+            "amount".to_string() => BaseType::Float,
+        }),
+    });
+    let valid = [
+        "SELECT category, SUM(amount) AS total FROM a JOIN b USING (id) \
+         WHERE amount > 0 GROUP BY 1 HAVING COUNT(*) > 1 ORDER BY total",
+        "SELECT UPPER(category) AS c, COUNT(*) AS n FROM a GROUP BY c ORDER BY 2",
+        "SELECT a.id, ROW_NUMBER() OVER (ORDER BY amount) AS r FROM a JOIN b ON a.id = b.id",
+        "SELECT a.id, COUNT(*) AS n FROM a JOIN b ON a.id = b.id GROUP BY a.id",
+        "SELECT category, COUNT(*) AS n FROM a GROUP BY a.category",
+        // Correlated subqueries in conditions
+        "SELECT id FROM a WHERE id IN (SELECT b.id FROM b WHERE b.id = a.id AND amount > 0)",
+        "SELECT a.id FROM a JOIN b ON a.id = b.id \
+         AND b.id IN (SELECT id FROM a AS a2 WHERE a2.category = a.category)",
+    ];
+    let invalid = [
+        "SELECT id FROM a WHERE category",
+        "SELECT a.id FROM a JOIN b ON a.id + b.id",
+        "SELECT id FROM a WHERE name = 'x'",
+        "SELECT category, id FROM a GROUP BY category",
+        "SELECT id, COUNT(*) AS n FROM a",
+        "SELECT category FROM a GROUP BY category HAVING id > 1",
+        "SELECT id FROM a ORDER BY 2",
+        "SELECT id FROM a ORDER BY name",
+        "SELECT id FROM a WHERE id IN (SELECT b.id FROM b WHERE b.id = a.amount)",
+        "SELECT id FROM a WHERE id IN (SELECT b.id FROM b WHERE b.amount = a.category)",
+        "SELECT b.id FROM a JOIN b ON a.id = b.id GROUP BY a.id",
+    ];
+    for (sql, ok) in valid
+        .iter()
+        .map(|x| (x, true))
+        .chain(invalid.iter().map(|x| (x, false)))
+    {
+        let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+            .tokenize()
+            .unwrap();
+        let mut parser = Parser::new(tokens);
+        let query = parser.parse_query().unwrap();
+
+        assert_eq!(
+            get_model_type(&query, type_env.clone()).is_ok(),
+            ok,
+            "{}",
+            sql
+        );
+    }
+}
+
+#[test]
+pub fn correlated_subqueries() {
+    let type_env = im::HashMap::from(hashmap! {
+        "customers".to_string() => TableType::Closed(hashmap! {
+            "id".to_string() => BaseType::Number,
+            "name".to_string() => BaseType::String,
+        }),
+        "orders".to_string() => TableType::Closed(hashmap! {
+            "id".to_string() => BaseType::Number,
+            "cid".to_string() => BaseType::Number,
+            "amount".to_string() => BaseType::Float,
+        }),
+    });
+    let sql = "SELECT id, (SELECT SUM(amount) FROM orders o WHERE o.cid = c.id) AS total \
+               FROM customers c WHERE EXISTS (SELECT 1 FROM orders o WHERE o.cid = c.id)";
+    let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+        .tokenize()
+        .unwrap();
+    let mut parser = Parser::new(tokens);
+    let query = parser.parse_query().unwrap();
+
+    assert_eq!(
+        get_model_type(&query, type_env.clone()).unwrap(),
+        TableType::Closed(hashmap! {
+            "id".to_string() => BaseType::Number,
+            "total".to_string() => BaseType::Nullable(Box::new(BaseType::Float)),
+        })
+    );
+
+    let valid = [
+        // Unqualified columns are looked up in the subquery first
+        "SELECT id FROM customers c WHERE EXISTS (SELECT 1 FROM orders WHERE cid = id)",
+        "SELECT id FROM customers WHERE EXISTS (SELECT 1 FROM orders o WHERE name <> '')",
+        "SELECT id FROM customers c WHERE EXISTS (SELECT 1 FROM orders o \
+         WHERE EXISTS (SELECT 1 FROM orders o2 WHERE o2.id = o.id AND o2.cid = c.id))",
+    ];
+    let invalid = [
+        "SELECT id FROM customers c WHERE EXISTS (SELECT 1 FROM orders o WHERE o.cid = x.id)",
+        "SELECT id FROM customers c WHERE EXISTS (SELECT 1 FROM orders o WHERE o.cid = c.cid)",
+        "SELECT id FROM customers c WHERE EXISTS (SELECT 1 FROM orders o WHERE c.name + 1 > 0)",
+        // The enclosing query can't refer to the tables of the subquery
+        "SELECT o.id FROM customers c WHERE EXISTS (SELECT 1 FROM orders o)",
+    ];
+    for (sql, ok) in valid
+        .iter()
+        .map(|x| (x, true))
+        .chain(invalid.iter().map(|x| (x, false)))
+    {
+        let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+            .tokenize()
+            .unwrap();
+        let mut parser = Parser::new(tokens);
+        let query = parser.parse_query().unwrap();
+
+        assert_eq!(
+            get_model_type(&query, type_env.clone()).is_ok(),
+            ok,
+            "{}",
+            sql
+        );
+    }
+}
+
+#[test]
+pub fn get_set_operation_type() {
+    let sql = "SELECT id, name FROM a UNION ALL SELECT 2.5, NULL FROM a \
+               UNION ALL VALUES (3, 'c') ORDER BY name";
+    let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+        .tokenize()
+        .unwrap();
+    let mut parser = Parser::new(tokens);
+    let query = parser.parse_query().unwrap();
+    let type_env = im::HashMap::from(hashmap! {
+        "a".to_string() => TableType::Closed(hashmap! {
+            "id".to_string() => BaseType::Number,
+            "name".to_string() => BaseType::String,
+        }),
+    });
+
+    assert_eq!(
+        get_model_type(&query, type_env.clone()),
+        Ok(TableType::Closed(hashmap! {
+            "id".to_string() => BaseType::Float,
+            "name".to_string() => BaseType::Any,
+        }))
+    );
+
+    for sql in &[
+        "SELECT id, name FROM a UNION SELECT id FROM a",
+        "SELECT id FROM a EXCEPT SELECT name FROM a",
+        "VALUES (1, 'a'), (2)",
+        "SELECT id FROM a UNION SELECT id FROM a ORDER BY name",
+    ] {
+        let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+            .tokenize()
+            .unwrap();
+        let mut parser = Parser::new(tokens);
+        let query = parser.parse_query().unwrap();
+
+        assert!(get_model_type(&query, type_env.clone()).is_err(), "{}", sql);
+    }
+}
+
+#[test]
+pub fn unknown_tables_cte_scope() {
+    let sql = "WITH t AS (SELECT a FROM src) SELECT a FROM t JOIN other ON 1=1 \
+               WHERE EXISTS (SELECT 1 FROM missing)";
+    let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+        .tokenize()
+        .unwrap();
+    let mut parser = Parser::new(tokens);
+    let query = parser.parse_query().unwrap();
+
+    assert_eq!(
+        unknown_tables(&query, &im::HashSet::from(hashset! {"src".to_string()})),
+        vec!["other".to_string(), "missing".to_string()]
+    );
+}
+
+#[test]
+pub fn unknown_tables_quoted() {
+    let sql = "SELECT a FROM \"Orders\" JOIN Orders ON 1=1 JOIN \"Other\" ON 1=1";
+    let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+        .tokenize()
+        .unwrap();
+    let mut parser = Parser::new(tokens);
+    let query = parser.parse_query().unwrap();
+
+    assert_eq!(
+        unknown_tables(&query, &im::HashSet::from(hashset! {"Orders".to_string()})),
+        vec!["\"Other\"".to_string()]
+    );
+}
+
+#[test]
+pub fn unknown_tables_all_clauses() {
+    let sql = "SELECT CASE WHEN EXISTS (SELECT 1 FROM in_case) THEN 1 END, \
+               COALESCE((SELECT MAX(a) FROM in_func), 0) \
+               FROM src JOIN other ON src.a IN (SELECT a FROM in_join) \
+               WHERE src.a IN (1, (SELECT MIN(a) FROM in_list)) \
+               GROUP BY (SELECT 1 FROM in_group_by) \
+               HAVING EXISTS (SELECT 1 FROM in_having) \
+               ORDER BY (SELECT 1 FROM in_order_by)";
+    let tokens = Tokenizer::new(&PowerSqlDialect {}, &sql)
+        .tokenize()
+        .unwrap();
+    let mut parser = Parser::new(tokens);
+    let query = parser.parse_query().unwrap();
+
+    let mut res = unknown_tables(&query, &im::HashSet::from(hashset! {"src".to_string()}));
+    res.sort();
+    assert_eq!(
+        res,
+        vec![
+            "in_case",
+            "in_func",
+            "in_group_by",
+            "in_having",
+            "in_join",
+            "in_list",
+            "in_order_by",
+            "other"
+        ]
+    );
+}